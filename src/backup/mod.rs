@@ -0,0 +1,591 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+
+use crate::compose_helper::{compose, compose_output, docker, resolve_project_name};
+use crate::registry;
+use crate::state::State;
+use crate::up::sync::{data_dirs, named_volumes};
+use crate::version::short_version;
+
+/// The first entry of a backup archive, describing what it holds.
+const METADATA_FILENAME: &str = "collie-backup.yaml";
+/// The dir in the archive holding the bind mounted data dirs by path relative to the target.
+const DATA_DIR: &str = "data";
+/// The dir in the archive holding a tar of every named volume by its compose key.
+const VOLUMES_DIR: &str = "volumes";
+/// The image of the helper container which reads and writes the named volumes.
+const HELPER_IMAGE: &str = "alpine:3";
+/// Replace the content of `/volume` with the tar `$1`, unpacked into the staging dir `$2`
+/// first so that the volume is only touched once it unpacked completely.
+const RESTORE_VOLUME_SCRIPT: &str = r#"set -e
+rm -rf /volume/.collie-restore-*
+mkdir "$2"
+tar -C "$2" -xf "$1"
+find /volume -mindepth 1 -maxdepth 1 ! -path "$2" -exec rm -rf {} +
+find "$2" -mindepth 1 -maxdepth 1 -exec mv {} /volume/ \;
+rmdir "$2"
+"#;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    app_id: String,
+    app_name: String,
+    version: String,
+    project_name: String,
+    /// The bind mounted data dirs by path relative to the target, `/` separated.
+    data_dirs: Vec<String>,
+    /// The docker names of the named volumes by their compose key.
+    volumes: BTreeMap<String, String>,
+    /// Seconds since the unix epoch.
+    timestamp: u64,
+    cli_version: String,
+}
+
+/// A scratch dir for the volume tars, removed when dropped.
+struct Staging(PathBuf);
+
+impl Staging {
+    async fn new() -> Result<Self> {
+        let path = env::temp_dir().join(format!("collie-backup-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&path)
+            .await
+            .with_context(|| format!("create staging dir: {}", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Where backups go without `--output-dir`, in the per-user data dir.
+fn default_output_dir(project: &str) -> Result<PathBuf> {
+    registry::data_dir()
+        .map(|v| v.join("backups").join(project))
+        .context("can't find the user data dir, pass --output-dir")
+}
+
+/// `rel_path` of an archive entry if it stays inside the dir it's unpacked to.
+fn safe_rel_path(rel_path: &Path) -> Option<&Path> {
+    rel_path
+        .components()
+        .all(|v| matches!(v, Component::Normal(_)))
+        .then_some(rel_path)
+}
+
+/// `dirs` in `target` by path relative to it, `/` separated.
+fn rel_paths(target: &Path, dirs: &[PathBuf]) -> Vec<String> {
+    dirs.iter()
+        .filter_map(|v| v.strip_prefix(target).ok())
+        .map(|v| {
+            v.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect()
+}
+
+/// Refuse to restore the `backed_up` data dirs unless each is one of the `current` data
+/// dirs of the deployment.
+fn check_data_dirs(backed_up: &[String], current: &[String]) -> Result<()> {
+    for rel_path in backed_up {
+        if safe_rel_path(Path::new(rel_path)).is_none() || !current.contains(rel_path) {
+            bail!("{rel_path} in the backup is not a data dir of the deployment");
+        }
+    }
+    Ok(())
+}
+
+/// Run `work` with the running services of `project` stopped, starting them again
+/// whatever came out of it.
+async fn with_stopped_services<F, R>(
+    target: &Path,
+    project: &str,
+    token: CancellationToken,
+    work: F,
+) -> Result<R>
+where
+    F: std::future::Future<Output = Result<R>>,
+{
+    let running = compose_output(
+        token.clone(),
+        target,
+        project,
+        ["ps", "--services", "--filter", "status=running"],
+    )
+    .await
+    .context("run 'docker[.exe] compose ps'")?;
+    let running: Vec<_> = running.split_whitespace().collect();
+    if running.is_empty() {
+        return work.await;
+    }
+    compose(
+        token.clone(),
+        target,
+        project,
+        ["stop"].iter().chain(&running),
+    )
+    .await
+    .context("run 'docker[.exe] compose stop'")?;
+    let result = work.await;
+    // only the services which ran before, the user may have stopped the others
+    let started = compose(token, target, project, ["start"].iter().chain(&running))
+        .await
+        .context("run 'docker[.exe] compose start'");
+    match (result, started) {
+        (Err(err), Err(start_err)) => Err(err.context(format!("{start_err:#}"))),
+        (result, started) => started.and(result),
+    }
+}
+
+/// Archive the data of the app deployed in `target` as the compose project `project`
+/// into a timestamped tar.gz in `output_dir`, by default below the user data dir. The
+/// services are stopped meanwhile. Returns the path of the archive.
+pub(crate) async fn create(
+    target: &Path,
+    project: &str,
+    output_dir: Option<&Path>,
+    token: CancellationToken,
+) -> Result<PathBuf> {
+    let state = State::load(target)
+        .await
+        .context("no deployment recorded")?;
+    let target = target.canonicalize().context("get target abs path")?;
+    let dirs = data_dirs(&target).await?;
+    let volumes = named_volumes(&target, project).await;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default();
+    let metadata = Metadata {
+        app_id: state.app_id,
+        app_name: state.app_name,
+        version: state.version,
+        project_name: project.to_owned(),
+        data_dirs: rel_paths(&target, &dirs),
+        volumes,
+        timestamp,
+        cli_version: short_version().to_owned(),
+    };
+
+    let output_dir = match output_dir {
+        Some(v) => v.to_path_buf(),
+        None => default_output_dir(project)?,
+    };
+    fs::create_dir_all(&output_dir)
+        .await
+        .with_context(|| format!("create output dir: {}", output_dir.display()))?;
+    // never overwrite an earlier backup taken within the same second
+    let stem = format!("{project}-{}-{timestamp}", metadata.version);
+    let mut archive = output_dir.join(format!("{stem}.tar.gz"));
+    for i in 1.. {
+        if !archive.exists() {
+            break;
+        }
+        archive = output_dir.join(format!("{stem}-{i}.tar.gz"));
+    }
+
+    // a crash or a cancel leaves no archive which looks complete
+    let partial = archive.with_extension("gz.partial");
+
+    let staging = Staging::new().await?;
+    let staging_mount = format!("{}:/backup", staging.0.display());
+    let result = with_stopped_services(&target, project, token.clone(), async {
+        for (name, docker_name) in &metadata.volumes {
+            info!("back up volume {docker_name}");
+            docker(
+                token.clone(),
+                [
+                    "run",
+                    "--rm",
+                    "-v",
+                    &format!("{docker_name}:/volume:ro"),
+                    "-v",
+                    &staging_mount,
+                    HELPER_IMAGE,
+                    "tar",
+                    "-C",
+                    "/volume",
+                    "-cf",
+                    &format!("/backup/{name}.tar"),
+                    ".",
+                ],
+            )
+            .await
+            .with_context(|| format!("back up volume {docker_name}"))?;
+        }
+
+        let content = serde_yaml::to_string(&metadata).context("serialize backup metadata")?;
+        let target = target.clone();
+        let staging = staging.0.clone();
+        let archive = partial.clone();
+        let volumes: Vec<_> = metadata.volumes.keys().cloned().collect();
+        let data_dirs = metadata.data_dirs.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let file = File::create(&archive)
+                .with_context(|| format!("create archive: {}", archive.display()))?;
+            let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+            builder.follow_symlinks(false);
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(timestamp);
+            builder
+                .append_data(&mut header, METADATA_FILENAME, content.as_bytes())
+                .context("add metadata")?;
+            for rel_path in &data_dirs {
+                info!("back up {rel_path}");
+                builder
+                    .append_dir_all(format!("{DATA_DIR}/{rel_path}"), target.join(rel_path))
+                    .with_context(|| format!("add data dir: {rel_path}"))?;
+            }
+            for name in &volumes {
+                builder
+                    .append_path_with_name(
+                        staging.join(format!("{name}.tar")),
+                        format!("{VOLUMES_DIR}/{name}.tar"),
+                    )
+                    .with_context(|| format!("add volume: {name}"))?;
+            }
+            builder
+                .into_inner()
+                .and_then(|v| v.finish())
+                .with_context(|| format!("write archive: {}", archive.display()))?;
+            Ok(())
+        })
+        .await
+        .context("join archive task")?
+    })
+    .await;
+    if let Err(err) = result {
+        let _ = fs::remove_file(&partial).await;
+        return Err(err);
+    }
+    fs::rename(&partial, &archive)
+        .await
+        .with_context(|| format!("rename {} to {}", partial.display(), archive.display()))?;
+    Ok(archive)
+}
+
+/// Back up the data of the app deployed in `target`, see [`create`].
+pub(crate) async fn backup<T: AsRef<Path>>(
+    target: T,
+    project_name: Option<&str>,
+    instance: Option<&str>,
+    output_dir: Option<&Path>,
+    token: CancellationToken,
+) -> Result<PathBuf> {
+    let target = target.as_ref();
+
+    if !target.exists() {
+        bail!("target dir {} does not exist", target.display());
+    }
+
+    let project = resolve_project_name(project_name, instance, target, None).await?;
+    create(target, &project, output_dir, token).await
+}
+
+/// The metadata of `archive`, which has to come first in it.
+fn read_metadata(archive: &Path) -> Result<Metadata> {
+    let file =
+        File::open(archive).with_context(|| format!("open archive: {}", archive.display()))?;
+    let mut entries = tar::Archive::new(GzDecoder::new(file));
+    let entry = entries
+        .entries()
+        .context("read archive")?
+        .next()
+        .context("the archive is empty")?
+        .context("read archive entry")?;
+    if entry.path().context("read entry path")?.as_ref() != Path::new(METADATA_FILENAME) {
+        bail!("{} is not a collie backup", archive.display());
+    }
+    let mut content = String::new();
+    { entry }
+        .read_to_string(&mut content)
+        .context("read backup metadata")?;
+    serde_yaml::from_str(&content).context("parse backup metadata")
+}
+
+/// Where the archive entry `path` is unpacked to, `None` for the entries which aren't.
+/// Data entries have to lie in one of the `data_dirs`.
+fn destination(
+    path: &Path,
+    target: &Path,
+    data_dirs: &[String],
+    staging: &Path,
+) -> Result<Option<PathBuf>> {
+    let dst = if let Ok(rel_path) = path.strip_prefix(DATA_DIR) {
+        safe_rel_path(rel_path)
+            .filter(|v| data_dirs.iter().any(|dir| v.starts_with(dir)))
+            .map(|v| target.join(v))
+    } else if let Ok(rel_path) = path.strip_prefix(VOLUMES_DIR) {
+        safe_rel_path(rel_path)
+            .filter(|v| v.components().count() == 1)
+            .map(|v| staging.join(v))
+    } else {
+        return Ok(None);
+    };
+    match dst {
+        Some(dst) => Ok(Some(dst)),
+        None => bail!("illegal entry in archive: {}", path.display()),
+    }
+}
+
+fn open_archive(archive: &Path) -> Result<tar::Archive<GzDecoder<File>>> {
+    let file =
+        File::open(archive).with_context(|| format!("open archive: {}", archive.display()))?;
+    Ok(tar::Archive::new(GzDecoder::new(file)))
+}
+
+/// Replace the `data_dirs` in `target` with the ones in `archive` and put the volume
+/// tars into `staging`. Every entry is checked before anything is removed.
+fn unpack(archive: &Path, target: &Path, data_dirs: &[String], staging: &Path) -> Result<()> {
+    let mut links = Vec::new();
+    for entry in open_archive(archive)?.entries().context("read archive")? {
+        let entry = entry.context("read archive entry")?;
+        let path = entry.path().context("read entry path")?.into_owned();
+        // nothing may be written through a symlink unpacked before
+        if entry.header().entry_type().is_hard_link()
+            || links.iter().any(|v| path.starts_with(v) && path != *v)
+        {
+            bail!("illegal entry in archive: {}", path.display());
+        }
+        destination(&path, target, data_dirs, staging)?;
+        if entry.header().entry_type().is_symlink() {
+            links.push(path);
+        }
+    }
+
+    for rel_path in data_dirs {
+        let dir = target.join(rel_path);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("remove data dir: {}", dir.display()))?;
+        }
+    }
+
+    for entry in open_archive(archive)?.entries().context("read archive")? {
+        let mut entry = entry.context("read archive entry")?;
+        let path = entry.path().context("read entry path")?.into_owned();
+        let Some(dst) = destination(&path, target, data_dirs, staging)? else {
+            continue;
+        };
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create dir: {}", parent.display()))?;
+        }
+        entry
+            .unpack(&dst)
+            .with_context(|| format!("unpack {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Put the data in `archive` back into the app deployed in `target`, replacing what is
+/// there, with its services stopped meanwhile. An archive of another app is refused
+/// unless `force`. Returns the version the backup was taken of.
+pub(crate) async fn restore<T: AsRef<Path>>(
+    target: T,
+    archive: &Path,
+    project_name: Option<&str>,
+    instance: Option<&str>,
+    force: bool,
+    token: CancellationToken,
+) -> Result<String> {
+    let target = target.as_ref();
+
+    if !target.exists() {
+        bail!("target dir {} does not exist", target.display());
+    }
+
+    let state = State::load(target)
+        .await
+        .context("no deployment recorded")?;
+    let target = target.canonicalize().context("get target abs path")?;
+    let archive = archive.to_path_buf();
+    let metadata = {
+        let archive = archive.clone();
+        tokio::task::spawn_blocking(move || {
+            read_metadata(&archive).with_context(|| format!("read backup {}", archive.display()))
+        })
+        .await
+        .context("join archive task")??
+    };
+    if metadata.app_id != state.app_id {
+        if !force {
+            bail!(
+                "the backup is of app {}, not of {} deployed in {}, pass --force if you are sure",
+                metadata.app_id,
+                state.app_id,
+                target.display()
+            );
+        }
+        warn!("restore a backup of app {} anyway", metadata.app_id);
+    }
+    if metadata.version != state.version {
+        warn!(
+            "the backup was taken of {}, {} is deployed",
+            metadata.version, state.version
+        );
+    }
+
+    let current = rel_paths(&target, &data_dirs(&target).await?);
+    check_data_dirs(&metadata.data_dirs, &current)
+        .with_context(|| format!("restore into {}", target.display()))?;
+
+    let project = resolve_project_name(project_name, instance, &target, None).await?;
+    let volumes = named_volumes(&target, &project).await;
+    let staging = Staging::new().await?;
+    let staging_mount = format!("{}:/backup", staging.0.display());
+    with_stopped_services(&target, &project, token.clone(), async {
+        let archive = archive.clone();
+        let unpack_target = target.clone();
+        let staging_dir = staging.0.clone();
+        let data_dirs = metadata.data_dirs.clone();
+        tokio::task::spawn_blocking(move || {
+            unpack(&archive, &unpack_target, &data_dirs, &staging_dir)
+        })
+        .await
+        .context("join archive task")??;
+
+        for name in metadata.volumes.keys() {
+            let Some(docker_name) = volumes.get(name) else {
+                warn!("skip volume {name}, the deployment doesn't declare it");
+                continue;
+            };
+            info!("restore volume {docker_name}");
+            docker(
+                token.clone(),
+                [
+                    "run",
+                    "--rm",
+                    "-v",
+                    &format!("{docker_name}:/volume"),
+                    "-v",
+                    &format!("{staging_mount}:ro"),
+                    HELPER_IMAGE,
+                    "sh",
+                    "-c",
+                    RESTORE_VOLUME_SCRIPT,
+                    "sh",
+                    &format!("/backup/{name}.tar"),
+                    &format!("/volume/.collie-restore-{}", uuid::Uuid::new_v4()),
+                ],
+            )
+            .await
+            .with_context(|| format!("restore volume {docker_name}"))?;
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(metadata.version)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const METADATA: &str = "app_id: a\napp_name: a\nversion: 1.0.0\nproject_name: a\n\
+                            data_dirs: [data]\nvolumes: {db: a_db}\ntimestamp: 0\ncli_version: ''\n";
+
+    /// Write an archive of the metadata and `entries` to `archive`, the paths are taken
+    /// as they are, even illegal ones.
+    fn write_archive(archive: &Path, entries: &[(&str, &str)]) {
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(archive).unwrap(),
+            Compression::default(),
+        ));
+        for (path, data) in [(METADATA_FILENAME, METADATA)].iter().chain(entries) {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, data.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    /// A target holding a stale file in its `data` dir, and an empty staging dir.
+    fn fixture() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("target");
+        let staging = root.path().join("staging");
+        std::fs::create_dir_all(target.join("data")).unwrap();
+        std::fs::create_dir_all(&staging).unwrap();
+        std::fs::write(target.join("data").join("stale"), "stale").unwrap();
+        (root, target, staging)
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let root = tempfile::tempdir().unwrap();
+        let archive = root.path().join("backup.tar.gz");
+        write_archive(&archive, &[]);
+        let metadata = read_metadata(&archive).unwrap();
+        assert_eq!(metadata.data_dirs, ["data"]);
+        assert_eq!(metadata.volumes["db"], "a_db");
+    }
+
+    #[test]
+    fn test_unpack() {
+        let (root, target, staging) = fixture();
+        let archive = root.path().join("backup.tar.gz");
+        write_archive(
+            &archive,
+            &[("data/data/kept", "kept"), ("volumes/db.tar", "tar")],
+        );
+        unpack(&archive, &target, &["data".to_owned()], &staging).unwrap();
+        assert!(!target.join("data").join("stale").exists());
+        assert_eq!(
+            std::fs::read_to_string(target.join("data").join("kept")).unwrap(),
+            "kept"
+        );
+        assert!(staging.join("db.tar").is_file());
+    }
+
+    #[test]
+    fn test_unpack_refuses_traversal() {
+        for path in [
+            "data/../../evil",
+            "data/other/evil",
+            "volumes/../evil",
+            "volumes/db/evil.tar",
+        ] {
+            let (root, target, staging) = fixture();
+            let archive = root.path().join("backup.tar.gz");
+            write_archive(&archive, &[("data/data/kept", "kept"), (path, "evil")]);
+            let result = unpack(&archive, &target, &["data".to_owned()], &staging);
+            assert!(result.is_err(), "{path}");
+            // refused before anything was removed
+            assert!(target.join("data").join("stale").is_file(), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_check_data_dirs() {
+        let current = ["data".to_owned(), "conf/db".to_owned()];
+        check_data_dirs(&["conf/db".to_owned()], &current).unwrap();
+        assert!(check_data_dirs(&["other".to_owned()], &current).is_err());
+        assert!(check_data_dirs(&["../data".to_owned()], &current).is_err());
+        assert!(check_data_dirs(&["/data".to_owned()], &current).is_err());
+    }
+}
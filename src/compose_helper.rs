@@ -1,5 +1,5 @@
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 
 use anyhow::{bail, Context, Result};
@@ -11,24 +11,100 @@ use tokio::process::Command;
 use tokio_util::sync::CancellationToken;
 use which::which;
 
+use crate::manifest::Manifest;
+use crate::state::State;
+use crate::{target_dir, MANIFEST_FILENAME};
+
 lazy_static::lazy_static! {
     static ref SERVER_REGEX: Regex = Regex::new(r"(0|[1-9]\d*)\.(0|[1-9]\d*)\.(0|[1-9]\d*)(?:-((?:0|[1-9]\d*|\d*[a-zA-Z-][0-9a-zA-Z-]*)(?:\.(?:0|[1-9]\d*|\d*[a-zA-Z-][0-9a-zA-Z-]*))*))?(?:\+([0-9a-zA-Z-]+(?:\.[0-9a-zA-Z-]+)*))?").unwrap();
     static ref COMPOSE_IN_DOCKER_VERSION: VersionReq = VersionReq::parse(">=20.10.13").unwrap();
 }
 
-pub(crate) async fn compose<T, I, S>(token: CancellationToken, target: T, args: I) -> Result<()>
-where
-    T: AsRef<Path>,
-    I: IntoIterator<Item = S>,
-    S: AsRef<OsStr>,
-{
-    // run with docker compose
+/// Turn `raw` into a legal compose project name: lowercase letters, digits, `-` and `_`
+/// starting with a letter or digit.
+pub(crate) fn sanitize_project_name(raw: &str) -> String {
+    raw.to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-' || *c == '_')
+        .collect::<String>()
+        .trim_start_matches(['-', '_'])
+        .to_owned()
+}
+
+/// The project name compose derives from the directory it runs in.
+pub(crate) fn project_name<T: AsRef<Path>>(target: T) -> String {
+    let target = target.as_ref();
+    let target = target
+        .canonicalize()
+        .unwrap_or_else(|_| target.to_path_buf());
+    let dir_name = target
+        .file_name()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_default();
+    sanitize_project_name(&dir_name)
+}
+
+/// The compose project name of the app deployed in `target`: `explicit` if given, else
+/// the one recorded for the deployment, else the one compose derives from `target` for a
+/// deployment which predates the state file, else one derived from the `app_id` or the
+/// name of `manifest` and the `instance`, else the one compose derives from `target`.
+pub(crate) async fn resolve_project_name(
+    explicit: Option<&str>,
+    instance: Option<&str>,
+    target: &Path,
+    manifest: Option<&Manifest>,
+) -> Result<String> {
+    if let Some(explicit) = explicit {
+        if explicit.is_empty() || sanitize_project_name(explicit) != explicit {
+            bail!("illegal project name '{explicit}', only a-z, 0-9, '-' and '_' are allowed");
+        }
+        return Ok(explicit.to_owned());
+    }
+    // keep the name of a running deployment even if the manifest changed since
+    if let Ok(state) = State::load(target).await {
+        if !state.project_name.is_empty() {
+            return Ok(state.project_name);
+        }
+    } else if target.join(MANIFEST_FILENAME).is_file() && !target_dir::is_marked(target) {
+        // deployed before the project name was derived from the app_id
+        return Ok(project_name(target));
+    }
+    let derived = manifest
+        .map(|v| {
+            if v.metadata.app_id.is_empty() {
+                sanitize_project_name(&v.metadata.name)
+            } else {
+                sanitize_project_name(&v.metadata.app_id)
+            }
+        })
+        .unwrap_or_default();
+    if derived.is_empty() {
+        return Ok(project_name(target));
+    }
+    match instance {
+        Some(instance) => Ok(format!("{derived}-{instance}")),
+        None => Ok(derived),
+    }
+}
+
+fn docker_cli() -> Result<PathBuf> {
     #[cfg(target_family = "windows")]
     let docker_cli = which("docker.exe").context("can't find your docker.exe program")?;
 
     #[cfg(target_family = "unix")]
     let docker_cli = which("docker").context("can't find your docker program")?;
 
+    Ok(docker_cli)
+}
+
+/// Find the compose program of this host and the args selecting it: `docker compose` for
+/// docker >= 20.10.13, else `docker-compose`. `None` when cancelled while checking.
+pub(crate) async fn compose_program(
+    token: CancellationToken,
+) -> Result<Option<(PathBuf, &'static [&'static str])>> {
+    // run with docker compose
+    let docker_cli = docker_cli()?;
+
     let wait_for_cancel = token.cancelled().fuse();
     pin_mut!(wait_for_cancel);
 
@@ -42,7 +118,7 @@ where
         .fuse();
     pin_mut!(command_fut);
     let docker_version_output = select! {
-        _ = wait_for_cancel => return Ok(()),
+        _ = wait_for_cancel => return Ok(None),
         result = command_fut => result.context("check docker version")?
     };
     let docker_version = String::from_utf8_lossy(&docker_version_output.stdout);
@@ -53,49 +129,266 @@ where
     let docker_version = Version::parse(docker_version.as_str()).unwrap();
     if COMPOSE_IN_DOCKER_VERSION.matches(&docker_version) {
         debug!("run with docker compose");
+        return Ok(Some((docker_cli, &["compose"])));
+    }
 
-        let command_fut = Command::new(docker_cli)
-            .arg("compose")
-            .args(args)
-            .current_dir(target)
-            .kill_on_drop(true)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status()
-            .fuse();
-        pin_mut!(command_fut);
-
-        select! {
-            _ = wait_for_cancel => return Ok(()),
-            result = command_fut => result?
-        };
-    } else {
-        #[cfg(target_family = "windows")]
-        let docker_compose_cli =
-            which("docker-compose.exe").context("can't find your docker-compose.exe program")?;
-
-        #[cfg(target_family = "unix")]
-        let docker_compose_cli =
-            which("docker-compose").context("can't find your docker-compose program")?;
-
-        debug!("run with docker-compose");
-
-        let command_fut = Command::new(docker_compose_cli)
-            .args(args)
-            .current_dir(target)
-            .kill_on_drop(true)
-            .stdin(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .status()
-            .fuse();
-        pin_mut!(command_fut);
-
-        select! {
-            _ = wait_for_cancel => return Ok(()),
-            result = command_fut => result?
+    #[cfg(target_family = "windows")]
+    let docker_compose_cli =
+        which("docker-compose.exe").context("can't find your docker-compose.exe program")?;
+
+    #[cfg(target_family = "unix")]
+    let docker_compose_cli =
+        which("docker-compose").context("can't find your docker-compose program")?;
+
+    debug!("run with docker-compose");
+    Ok(Some((docker_compose_cli, &[])))
+}
+
+/// Run compose with `args` in `target` for the compose project `project`.
+pub(crate) async fn compose<T, I, S>(
+    token: CancellationToken,
+    target: T,
+    project: &str,
+    args: I,
+) -> Result<()>
+where
+    T: AsRef<Path>,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let Some((compose_cli, compose_args)) = compose_program(token.clone()).await? else {
+        bail!("cancelled");
+    };
+    run_compose(
+        token,
+        &compose_cli,
+        compose_args,
+        target.as_ref(),
+        project,
+        args,
+    )
+    .await
+}
+
+/// Run compose with `args` in `target` for the compose project `project` and return what
+/// it prints, failing unless it exits successfully.
+pub(crate) async fn compose_output<T, I, S>(
+    token: CancellationToken,
+    target: T,
+    project: &str,
+    args: I,
+) -> Result<String>
+where
+    T: AsRef<Path>,
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let Some((compose_cli, compose_args)) = compose_program(token.clone()).await? else {
+        bail!("cancelled");
+    };
+    let wait_for_cancel = token.cancelled().fuse();
+    pin_mut!(wait_for_cancel);
+
+    let command_fut = Command::new(compose_cli)
+        .args(compose_args)
+        .args(["-p", project])
+        .args(args)
+        .current_dir(target)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .output()
+        .fuse();
+    pin_mut!(command_fut);
+
+    let output = select! {
+        _ = wait_for_cancel => bail!("cancelled"),
+        result = command_fut => result.context("run compose")?
+    };
+    if !output.status.success() {
+        bail!("compose exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run the compose program `compose_cli` selected by `compose_args`, failing unless it
+/// exits successfully.
+pub(crate) async fn run_compose<I, S>(
+    token: CancellationToken,
+    compose_cli: &Path,
+    compose_args: &[&str],
+    target: &Path,
+    project: &str,
+    args: I,
+) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let wait_for_cancel = token.cancelled().fuse();
+    pin_mut!(wait_for_cancel);
+
+    let command_fut = Command::new(compose_cli)
+        .args(compose_args)
+        .args(["-p", project])
+        .args(args)
+        .current_dir(target)
+        .kill_on_drop(true)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .fuse();
+    pin_mut!(command_fut);
+
+    let status = select! {
+        _ = wait_for_cancel => bail!("cancelled"),
+        result = command_fut => result.context("run compose")?
+    };
+    if !status.success() {
+        bail!("compose exited with {status}");
+    }
+    Ok(())
+}
+
+/// Run docker with `args`, failing unless it exits successfully.
+pub(crate) async fn docker<I, S>(token: CancellationToken, args: I) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let wait_for_cancel = token.cancelled().fuse();
+    pin_mut!(wait_for_cancel);
+
+    let command_fut = Command::new(docker_cli()?)
+        .args(args)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .fuse();
+    pin_mut!(command_fut);
+
+    let status = select! {
+        _ = wait_for_cancel => bail!("cancelled"),
+        result = command_fut => result.context("run docker")?
+    };
+    if !status.success() {
+        bail!("docker exited with {status}");
+    }
+    Ok(())
+}
+
+/// Run docker with `args` and return what it prints, failing unless it exits successfully.
+async fn docker_output<I, S>(token: CancellationToken, args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let wait_for_cancel = token.cancelled().fuse();
+    pin_mut!(wait_for_cancel);
+
+    let command_fut = Command::new(docker_cli()?)
+        .args(args)
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .output()
+        .fuse();
+    pin_mut!(command_fut);
+
+    let output = select! {
+        _ = wait_for_cancel => bail!("cancelled"),
+        result = command_fut => result.context("run docker")?
+    };
+    if !output.status.success() {
+        bail!("docker exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Remove what compose created for `project` by its project label, the way `compose down`
+/// does but without a compose file, which docker-compose v1 can't do without.
+pub(crate) async fn down_by_label(
+    token: CancellationToken,
+    project: &str,
+    volumes: bool,
+) -> Result<()> {
+    let filter = format!("label=com.docker.compose.project={project}");
+    let mut kinds = vec![("container", ["rm", "-f"]), ("network", ["network", "rm"])];
+    if volumes {
+        kinds.push(("volume", ["volume", "rm"]));
+    }
+    for (kind, remove) in kinds {
+        let list = match kind {
+            "container" => vec!["ps", "-aq", "--filter", &filter],
+            kind => vec![kind, "ls", "-q", "--filter", &filter],
         };
+        let ids = docker_output(token.clone(), &list)
+            .await
+            .with_context(|| format!("list the {kind}s of project {project}"))?;
+        let ids: Vec<_> = ids.split_whitespace().collect();
+        if ids.is_empty() {
+            continue;
+        }
+        docker(token.clone(), remove.iter().chain(&ids))
+            .await
+            .with_context(|| format!("remove the {kind}s of project {project}"))?;
     }
     Ok(())
 }
+
+#[cfg(all(test, target_family = "unix"))]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn stub(dir: &Path, exit_code: i32) -> PathBuf {
+        let path = dir.join(format!("compose-{exit_code}"));
+        std::fs::write(&path, format!("#!/bin/sh\nexit {exit_code}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn test_run_compose_fails_on_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let failing = stub(dir.path(), 3);
+        let result = run_compose(
+            CancellationToken::new(),
+            &failing,
+            &[],
+            dir.path(),
+            "demo",
+            ["up", "-d"],
+        )
+        .await;
+        assert!(result.is_err());
+
+        let passing = stub(dir.path(), 0);
+        run_compose(
+            CancellationToken::new(),
+            &passing,
+            &[],
+            dir.path(),
+            "demo",
+            ["up", "-d"],
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_compose_fails_on_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let passing = stub(dir.path(), 0);
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = run_compose(token, &passing, &[], dir.path(), "demo", ["up", "-d"]).await;
+        assert!(result.is_err());
+    }
+}
@@ -1,42 +1,241 @@
-use std::ffi::OsStr;
+use std::env;
 use std::path::Path;
-use std::process::Stdio;
 
 use anyhow::{bail, Context, Result};
-use tokio::process::Command;
+use futures::{pin_mut, select, FutureExt};
+use log::{info, warn};
+use tokio::fs;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio_util::sync::CancellationToken;
-use which::which;
 
-use crate::compose_helper::compose;
+use crate::compose_helper::{
+    compose,
+    compose_program,
+    down_by_label,
+    resolve_project_name,
+    run_compose,
+};
+use crate::hook::{contract_envs, manifest_envs, run_hook, Action, Phase};
+use crate::manifest::{Hooks, Manifest};
+use crate::state::State;
+use crate::up::sync::data_dirs;
+use crate::{registry, target_dir};
 
-pub(super) async fn down<T: AsRef<Path>>(target: T, token: CancellationToken) -> Result<()> {
+/// How [`down`] takes the app down and what data it removes along with it.
+pub(super) struct Options {
+    /// The compose project name instead of the one [`resolve_project_name`] finds.
+    pub(super) project_name: Option<String>,
+    /// The named instance deployed in the target.
+    pub(super) instance: Option<String>,
+    /// Remove the named volumes of the app too.
+    pub(super) volumes: bool,
+    /// Delete the bind mounted dirs inside the target.
+    pub(super) purge_data: bool,
+    /// Delete the target dir itself afterwards.
+    pub(super) remove_render_dir: bool,
+    /// Don't ask before removing anything.
+    pub(super) yes: bool,
+}
+
+/// Ask `question` on the terminal, anything but `y` or `yes` is a no.
+async fn confirm(question: &str, token: CancellationToken) -> Result<bool> {
+    let mut stdout = io::stdout();
+    stdout
+        .write_all(format!("{question} [y/N] ").as_bytes())
+        .await
+        .context("write prompt")?;
+    stdout.flush().await.context("flush prompt")?;
+
+    let mut answer = String::new();
+    let mut stdin = BufReader::new(io::stdin());
+    let read_answer = stdin.read_line(&mut answer).fuse();
+    pin_mut!(read_answer);
+    select! {
+        _ = token.cancelled().fuse() => return Ok(false),
+        result = read_answer => result.context("read answer")?,
+    };
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Ask before removing `removals` unless `yes`, bail if the user says no.
+async fn confirm_removals(removals: &[String], yes: bool, token: CancellationToken) -> Result<()> {
+    if removals.is_empty() || yes {
+        return Ok(());
+    }
+    println!("This removes, for good:");
+    for removal in removals {
+        println!("  {removal}");
+    }
+    if !confirm("Continue?", token).await? {
+        bail!("aborted, pass --yes to skip the confirmation");
+    }
+    Ok(())
+}
+
+fn compose_down_args(options: &Options) -> Vec<&'static str> {
+    let mut args = vec!["down"];
+    if options.volumes {
+        args.push("--volumes");
+    }
+    args
+}
+
+/// Take down the app whose render dir `target` is gone by the compose project name it
+/// was registered with, there are neither hooks nor data dirs left to care about.
+async fn down_without_target(
+    target: &Path,
+    options: &Options,
+    token: CancellationToken,
+) -> Result<()> {
+    let project = match &options.project_name {
+        Some(v) => v.clone(),
+        None => registry::find(target)
+            .await
+            .map(|v| v.project_name)
+            .with_context(|| {
+                format!(
+                    "{} does not exist and no deployment of it is registered, pass --project-name",
+                    target.display()
+                )
+            })?,
+    };
+    warn!(
+        "{} does not exist, skip the hooks and only remove project {project}",
+        target.display()
+    );
+
+    let mut removals = Vec::new();
+    if options.volumes {
+        removals.push(format!("the named volumes of project {project}"));
+    }
+    confirm_removals(&removals, options.yes, token.clone()).await?;
+
+    let Some((compose_cli, compose_args)) = compose_program(token.clone()).await? else {
+        bail!("cancelled");
+    };
+    // docker-compose v1 refuses to run without a compose file, do its job by the labels
+    if compose_args.is_empty() {
+        return down_by_label(token, &project, options.volumes).await;
+    }
+    // compose finds the containers by the project label, keep it away from compose files
+    let args = compose_down_args(options);
+    run_compose(
+        token,
+        &compose_cli,
+        compose_args,
+        &env::temp_dir(),
+        &project,
+        &args,
+    )
+    .await
+    .with_context(|| format!("run 'docker compose {}'", args.join(" ")))
+}
+
+pub(super) async fn down<P: AsRef<Path>, T: AsRef<Path>>(
+    dir: P,
+    target: T,
+    options: Options,
+    token: CancellationToken,
+) -> Result<()> {
+    let dir = dir.as_ref();
     let target = target.as_ref();
 
-    // check if target exist and create it
     if !target.exists() {
-        bail!("targe not exist");
+        return down_without_target(target, &options, token).await;
     }
 
-    compose(token.clone(), &target, ["down"])
-        .await
-        .context("run 'docker[.exe] compose down'")?;
-
-    // run custom uninstall script
-    let uninstall_script_file = target
-        .join("scripts")
-        .join("uninstall.sh")
-        .canonicalize()
-        .context("uninstall.sh path illegal")?;
-    let shell = which("sh").context("can't find your sh")?;
-    Command::new(shell)
-        .args([OsStr::new("-c"), uninstall_script_file.as_os_str()])
-        .current_dir(target)
-        .kill_on_drop(true)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()
+    let mut manifest = match Manifest::from_dir(target).await {
+        Ok(v) => Some(v),
+        Err(err) => {
+            warn!("can't load the deployed manifest: {err:#}");
+            None
+        },
+    };
+    let state = State::load(target).await.ok();
+    // the ports it was deployed with, an instance got its own ones
+    if let (Some(manifest), Some(state)) = (&mut manifest, &state) {
+        for (name, port) in &mut manifest.ports {
+            if let Some(recorded) = state.ports.get(name) {
+                port.port = *recorded;
+            }
+        }
+    }
+    let legacy_hooks = Hooks::legacy();
+    let hooks = manifest.as_ref().map_or(&legacy_hooks, |v| &v.hooks);
+    let project = resolve_project_name(
+        options.project_name.as_deref(),
+        options.instance.as_deref(),
+        target,
+        manifest.as_ref(),
+    )
+    .await?;
+
+    if options.remove_render_dir {
+        target_dir::guard_removal(dir, target).await?;
+    }
+    let data_dirs = if options.purge_data {
+        data_dirs(target).await?
+    } else {
+        Vec::new()
+    };
+    let mut removals = Vec::new();
+    if options.volumes {
+        removals.push(format!("the named volumes of project {project}"));
+    }
+    removals.extend(data_dirs.iter().map(|v| v.display().to_string()));
+    if options.remove_render_dir {
+        removals.push(format!("the render dir {}", target.display()));
+    }
+    confirm_removals(&removals, options.yes, token.clone()).await?;
+
+    // the hooks ran with the last down already, only compose is safe to repeat
+    let already_down = state.as_ref().map_or(false, |v| v.action == Action::Down);
+    if already_down {
+        info!(
+            "the app in {} is already down, skip the hooks",
+            target.display()
+        );
+    }
+
+    let mut envs = contract_envs(Action::Down, target, manifest.as_ref(), None, &project);
+    envs.extend(manifest.as_ref().map(manifest_envs).unwrap_or_default());
+
+    if !already_down {
+        run_hook(target, hooks, Phase::PreDown, &envs, token.clone()).await?;
+    }
+
+    let args = compose_down_args(&options);
+    compose(token.clone(), &target, &project, &args)
         .await
-        .with_context(|| format!("run 'sh -c {}'", uninstall_script_file.display()))?;
+        .with_context(|| format!("run 'docker[.exe] compose {}'", args.join(" ")))?;
+
+    if !already_down {
+        run_hook(target, hooks, Phase::PostDown, &envs, token.clone()).await?;
+    }
+
+    for dir in &data_dirs {
+        fs::remove_dir_all(dir)
+            .await
+            .with_context(|| format!("remove data dir: {}", dir.display()))?;
+        info!("removed {}", dir.display());
+    }
+
+    if options.remove_render_dir {
+        fs::remove_dir_all(target)
+            .await
+            .with_context(|| format!("remove render dir: {}", target.display()))?;
+        info!("removed {}", target.display());
+        return Ok(());
+    }
+
+    let mut state = match (state, &manifest) {
+        (Some(state), _) => state,
+        (None, Some(manifest)) => State::new(Action::Down, target, manifest, &project).await?,
+        // nothing to record without a manifest
+        (None, None) => return Ok(()),
+    };
+    state.action = Action::Down;
+    state.save(target).await.context("save deployment state")?;
+    registry::record(None, target, &state).await;
     Ok(())
 }
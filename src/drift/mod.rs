@@ -0,0 +1,21 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tokio_util::sync::CancellationToken;
+
+use crate::state::{Drift, State};
+
+/// The files in `target` which changed since the last recorded render.
+pub(super) async fn drift<T: AsRef<Path>>(
+    target: T,
+    token: CancellationToken,
+) -> Result<Vec<Drift>> {
+    let target = target.as_ref();
+    let state = State::load(target)
+        .await
+        .context("no deployment recorded")?;
+    if token.is_cancelled() {
+        return Ok(Vec::new());
+    }
+    state.drift(target).await
+}
@@ -0,0 +1,662 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{future, pin_mut, select, FutureExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+use tokio_util::sync::CancellationToken;
+use which::which;
+
+use crate::compose_helper::compose_program;
+use crate::manifest::{ContainerMode, HookContainer, Hooks, Manifest};
+
+/// The private env file holding the resolved variables and secrets in the target dir.
+pub(crate) const ENV_FILENAME: &str = ".collie.env";
+
+/// Turn a manifest key like `tcp-keepalive` into an env name like `COLLIE_VAR_TCP_KEEPALIVE`.
+pub(crate) fn env_key(prefix: &str, name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{prefix}{name}")
+}
+
+/// The lifecycle action a hook runs for, exposed as `COLLIE_ACTION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Action {
+    Up,
+    Down,
+    Upgrade,
+}
+
+impl Action {
+    /// The phase which runs right before the compose command of the action.
+    pub(crate) fn pre_phase(self) -> Phase {
+        match self {
+            Action::Up => Phase::PreUp,
+            Action::Down => Phase::PreDown,
+            Action::Upgrade => Phase::PreUpgrade,
+        }
+    }
+
+    /// The phase which runs right after the compose command of the action.
+    pub(crate) fn post_phase(self) -> Phase {
+        match self {
+            Action::Up => Phase::PostUp,
+            Action::Down => Phase::PostDown,
+            Action::Upgrade => Phase::PostUpgrade,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Up => "up",
+            Action::Down => "down",
+            Action::Upgrade => "upgrade",
+        })
+    }
+}
+
+/// The lifecycle phases a hook can be declared for, exposed as `COLLIE_HOOK`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    PreRender,
+    PostRender,
+    PreUp,
+    PostUp,
+    PreDown,
+    PostDown,
+    PreUpgrade,
+    PostUpgrade,
+}
+
+impl Phase {
+    pub(crate) const ALL: [Phase; 8] = [
+        Phase::PreRender,
+        Phase::PostRender,
+        Phase::PreUp,
+        Phase::PostUp,
+        Phase::PreDown,
+        Phase::PostDown,
+        Phase::PreUpgrade,
+        Phase::PostUpgrade,
+    ];
+
+    /// Whether the containers of the app can be expected to run, the first deploy has none
+    /// before `post_up` and `post_down` runs after they are removed.
+    pub(crate) fn has_running_containers(self) -> bool {
+        !matches!(
+            self,
+            Phase::PreRender | Phase::PostRender | Phase::PreUp | Phase::PostDown
+        )
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::PreRender => "pre_render",
+            Phase::PostRender => "post_render",
+            Phase::PreUp => "pre_up",
+            Phase::PostUp => "post_up",
+            Phase::PreDown => "pre_down",
+            Phase::PostDown => "post_down",
+            Phase::PreUpgrade => "pre_upgrade",
+            Phase::PostUpgrade => "post_upgrade",
+        })
+    }
+}
+
+/// The resolved ports and variables of `manifest`, `COLLIE_PORT_<NAME>`,
+/// `COLLIE_PORT_<NAME>_IP` and `COLLIE_VAR_<NAME>`.
+pub(crate) fn manifest_envs(manifest: &Manifest) -> Vec<(String, String)> {
+    let mut envs = Vec::new();
+    for (name, port) in &manifest.ports {
+        let port_key = env_key("COLLIE_PORT_", name);
+        envs.push((format!("{port_key}_IP"), port.ip.clone()));
+        envs.push((port_key, port.port.to_string()));
+    }
+    for (name, variable) in &manifest.variables {
+        envs.push((env_key("COLLIE_VAR_", name), variable.value.clone()));
+    }
+    envs.sort_unstable();
+    envs
+}
+
+/// The `COLLIE_*` variables describing the app and the action which every hook receives
+/// besides the ones from [`manifest_envs`]. The app variables are empty when there is no
+/// manifest and `COLLIE_PREV_VERSION` is empty when nothing was deployed before.
+/// `COMPOSE_PROJECT_NAME` makes compose commands of the hook target the app's project.
+pub(crate) fn contract_envs(
+    action: Action,
+    target: &Path,
+    manifest: Option<&Manifest>,
+    previous_version: Option<&str>,
+    project: &str,
+) -> Vec<(String, String)> {
+    let target_dir = target
+        .canonicalize()
+        .unwrap_or_else(|_| target.to_path_buf());
+    let metadata = manifest.map(|v| &v.metadata);
+    vec![
+        ("COLLIE_ACTION".to_owned(), action.to_string()),
+        (
+            "COLLIE_APP_ID".to_owned(),
+            metadata.map(|v| v.app_id.clone()).unwrap_or_default(),
+        ),
+        (
+            "COLLIE_APP_NAME".to_owned(),
+            metadata.map(|v| v.name.clone()).unwrap_or_default(),
+        ),
+        (
+            "COLLIE_APP_VERSION".to_owned(),
+            metadata.map(|v| v.version.clone()).unwrap_or_default(),
+        ),
+        (
+            "COLLIE_PREV_VERSION".to_owned(),
+            previous_version.unwrap_or_default().to_owned(),
+        ),
+        (
+            "COLLIE_TARGET_DIR".to_owned(),
+            target_dir.to_string_lossy().to_string(),
+        ),
+        ("COLLIE_PROJECT_NAME".to_owned(), project.to_owned()),
+        ("COMPOSE_PROJECT_NAME".to_owned(), project.to_owned()),
+    ]
+}
+
+/// Write `envs` to the env file in `target` which only the current user can read, so a
+/// hook can `source` it instead of relying on its own environment.
+pub(crate) async fn write_env_file<T: AsRef<Path>>(
+    target: T,
+    envs: &[(String, String)],
+) -> Result<()> {
+    let env_file = target.as_ref().join(ENV_FILENAME);
+    let content: String = envs
+        .iter()
+        .map(|(k, v)| format!("{k}='{}'\n", v.replace('\'', r"'\''")))
+        .collect();
+    write_private_file(&env_file, &content).await
+}
+
+/// Write `content` to `path` which only the current user can read.
+pub(crate) async fn write_private_file(path: &Path, content: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    options.mode(0o600);
+    let mut file = options
+        .open(path)
+        .await
+        .with_context(|| format!("open {}", path.display()))?;
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // the file may have been created with looser permissions before
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await
+            .with_context(|| format!("set {} permissions", path.display()))?;
+    }
+    file.write_all(content.as_bytes())
+        .await
+        .with_context(|| format!("write {}", path.display()))?;
+    Ok(())
+}
+
+/// Where the scripts dir is mounted in the one-shot container of a hook.
+const CONTAINER_HOOKS_DIR: &str = "/collie/hooks";
+
+/// `path` as the source of a short syntax bind mount. On windows that's without the
+/// verbatim `\\?\` prefix of canonical paths and with forward slashes, `C:/dir`.
+fn mount_source(path: &Path) -> String {
+    let source = path.display().to_string();
+
+    #[cfg(target_family = "windows")]
+    let source = source
+        .strip_prefix(r"\\?\")
+        .unwrap_or(&source)
+        .replace('\\', "/");
+
+    source
+}
+
+/// Build the compose command which runs `script_file` with `interpreter` (`sh` if absent) in
+/// a container of `container.service`. `exec` pipes the script into the running container,
+/// `run` starts a one-shot container with the scripts dir mounted. `envs` are passed into
+/// the container by name, compose reads their values from its own env, where their
+/// `COMPOSE_PROJECT_NAME` selects the project.
+async fn container_command(
+    phase: Phase,
+    script_file: &Path,
+    interpreter: Option<&str>,
+    container: &HookContainer,
+    envs: &[(String, String)],
+    token: CancellationToken,
+) -> Result<std::process::Command> {
+    let Some((compose_cli, compose_args)) = compose_program(token).await? else {
+        bail!("{phase} hook {} cancelled", script_file.display());
+    };
+    let interpreter = interpreter.unwrap_or("sh");
+
+    let mut command = std::process::Command::new(compose_cli);
+    command.args(compose_args);
+    match container.mode {
+        ContainerMode::Exec => {
+            let script = fs::File::open(script_file)
+                .await
+                .with_context(|| format!("open {phase} hook {}", script_file.display()))?;
+            command.args(["exec", "-T"]);
+            command.stdin(script.into_std().await);
+        },
+        ContainerMode::Run => {
+            let scripts_dir = script_file.parent().unwrap_or(script_file);
+            command.args([
+                "run",
+                "--rm",
+                "--no-deps",
+                "-T",
+                "--entrypoint",
+                interpreter,
+            ]);
+            command.stdin(Stdio::null());
+            command.arg("--volume");
+            command.arg(format!(
+                "{}:{CONTAINER_HOOKS_DIR}:ro",
+                mount_source(scripts_dir)
+            ));
+        },
+    }
+    // only the names, compose takes the values from the env `run_hook` gives it so that
+    // no secret shows up in the process list
+    let names = envs.iter().map(|(k, _)| k.as_str()).chain(["COLLIE_HOOK"]);
+    for name in names {
+        command.args(["-e", name]);
+    }
+    command.arg(&container.service);
+    match container.mode {
+        // read the script from stdin
+        ContainerMode::Exec => command.args([interpreter, "-"]),
+        ContainerMode::Run => command.arg(format!(
+            "{CONTAINER_HOOKS_DIR}/{}",
+            script_file
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        )),
+    };
+    Ok(command)
+}
+
+/// How long a hook may clean up after SIGTERM before its process group is killed.
+const HOOK_KILL_GRACE: Duration = Duration::from_secs(5);
+
+enum Outcome {
+    Exited(std::io::Result<ExitStatus>),
+    TimedOut(u64),
+    Cancelled,
+}
+
+/// Stop a running hook: SIGTERM to its whole process group, then SIGKILL if it's still
+/// alive after [`HOOK_KILL_GRACE`].
+async fn terminate(child: &mut Child) -> Result<()> {
+    #[cfg(target_family = "unix")]
+    if let Some(pid) = child.id() {
+        // the hook leads its own process group, so everything it spawned is signalled too
+        let pgid = -(pid as libc::pid_t);
+        unsafe { libc::kill(pgid, libc::SIGTERM) };
+        let exited = tokio::time::timeout(HOOK_KILL_GRACE, child.wait())
+            .await
+            .is_ok();
+        if !exited {
+            warn!("hook still running {HOOK_KILL_GRACE:?} after SIGTERM, send SIGKILL");
+        }
+        // also reap the stragglers which outlived the script itself
+        unsafe { libc::kill(pgid, libc::SIGKILL) };
+        child.wait().await.context("wait for killed hook")?;
+        return Ok(());
+    }
+    child.kill().await.context("kill hook")
+}
+
+/// Add the execute bits matching the read bits of `script_file` if it has none, so that
+/// hooks copied without them can still run themselves.
+#[cfg(target_family = "unix")]
+async fn ensure_executable(script_file: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(script_file)
+        .await
+        .with_context(|| format!("get {} metadata", script_file.display()))?
+        .permissions();
+    let mode = permissions.mode();
+    if mode & 0o111 == 0 {
+        permissions.set_mode(mode | 0o100 | (mode & 0o044) >> 2);
+        fs::set_permissions(script_file, permissions)
+            .await
+            .with_context(|| format!("set {} permissions", script_file.display()))?;
+    }
+    Ok(())
+}
+
+/// Split the `#!` line at the top of a script into the program and its arguments.
+fn parse_shebang(content: &str) -> Option<(String, Vec<String>)> {
+    let line = content.lines().next()?.strip_prefix("#!")?;
+    let mut parts = line.split_whitespace();
+    let program = parts.next()?.to_owned();
+    Some((program, parts.map(str::to_owned).collect()))
+}
+
+fn find_interpreter(phase: Phase, program: &str, origin: &str) -> Result<PathBuf> {
+    which(program).map_err(|_| {
+        anyhow!("{phase} hook needs interpreter '{program}' ({origin}) which is not installed or not in PATH")
+    })
+}
+
+/// Build the command which runs `script_file`. The interpreter is the one declared in the
+/// manifest, else the shebang of the script on unix or the one matching the `.ps1`, `.cmd`
+/// or `.bat` extension on windows, else `sh`.
+async fn hook_command(
+    phase: Phase,
+    script_file: &Path,
+    interpreter: Option<&str>,
+) -> Result<std::process::Command> {
+    if let Some(interpreter) = interpreter {
+        let program = find_interpreter(phase, interpreter, "declared in the manifest")?;
+        let mut command = std::process::Command::new(program);
+        command.arg(script_file);
+        return Ok(command);
+    }
+
+    #[cfg(target_family = "windows")]
+    {
+        let extension = script_file
+            .extension()
+            .and_then(|v| v.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("ps1") => {
+                let program = which("pwsh").or_else(|_| which("powershell")).map_err(|_| {
+                    anyhow!("{phase} hook needs interpreter 'pwsh' or 'powershell' which is not installed or not in PATH")
+                })?;
+                let mut command = std::process::Command::new(program);
+                command.args(["-NoProfile", "-ExecutionPolicy", "Bypass", "-File"]);
+                command.arg(script_file);
+                return Ok(command);
+            },
+            Some("cmd" | "bat") => {
+                let program = find_interpreter(phase, "cmd", "for .cmd and .bat scripts")?;
+                let mut command = std::process::Command::new(program);
+                command.arg("/C").arg(script_file);
+                return Ok(command);
+            },
+            _ => {},
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    {
+        let content = fs::read(script_file)
+            .await
+            .with_context(|| format!("read {phase} hook {}", script_file.display()))?;
+        if let Some((mut program, mut args)) = parse_shebang(&String::from_utf8_lossy(&content)) {
+            // look up `#!/usr/bin/env python3` ourselves to report a missing python3 clearly
+            if Path::new(&program).ends_with("env")
+                && args.first().map_or(false, |v| !v.starts_with('-'))
+            {
+                program = args.remove(0);
+            }
+            let program = find_interpreter(phase, &program, "from the shebang")?;
+            let mut command = std::process::Command::new(program);
+            command.args(args).arg(script_file);
+            return Ok(command);
+        }
+    }
+
+    let shell = find_interpreter(phase, "sh", "the default")?;
+    let mut command = std::process::Command::new(shell);
+    command.arg(script_file);
+    Ok(command)
+}
+
+/// Run the hook declared for `phase` inside `target`, adding `envs` and `COLLIE_HOOK` to
+/// the inherited environment. `COLLIE_ENV_FILE` points to the env file if there is one.
+/// Nothing happens when no hook is declared for `phase`. A hook with `container` runs
+/// through compose in a container of the app instead of on the host.
+///
+/// The hook is terminated when it runs longer than its timeout or `token` is cancelled.
+pub(crate) async fn run_hook<T: AsRef<Path>>(
+    target: T,
+    hooks: &Hooks,
+    phase: Phase,
+    envs: &[(String, String)],
+    token: CancellationToken,
+) -> Result<()> {
+    let Some(hook) = hooks.get(phase) else {
+        debug!("no {phase} hook declared");
+        return Ok(());
+    };
+    let target = target.as_ref();
+    let script_file = target.join(&hook.path);
+    if !script_file.is_file() {
+        if hook.required {
+            bail!(
+                "{phase} hook {} is required but missing",
+                hook.path.display()
+            );
+        }
+        warn!("skip {phase} hook, {} is missing", hook.path.display());
+        return Ok(());
+    }
+    let script_file = script_file
+        .canonicalize()
+        .with_context(|| format!("{phase} hook {} path illegal", hook.path.display()))?;
+
+    #[cfg(target_family = "unix")]
+    ensure_executable(&script_file).await?;
+
+    let interpreter = hook.interpreter.as_deref();
+    let mut command = match &hook.container {
+        Some(container) => {
+            container_command(
+                phase,
+                &script_file,
+                interpreter,
+                container,
+                envs,
+                token.clone(),
+            )
+            .await?
+        },
+        None => {
+            let mut command = hook_command(phase, &script_file, interpreter).await?;
+            // a hook in its own process group would stop on reading the terminal
+            command.stdin(Stdio::null());
+            command
+        },
+    };
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::process::CommandExt;
+
+        command.process_group(0);
+    }
+    let env_file = target.join(ENV_FILENAME);
+    if env_file.is_file() {
+        let env_file = env_file.canonicalize().context("get env file abs path")?;
+        command.env("COLLIE_ENV_FILE", env_file);
+    }
+    command.env("COLLIE_HOOK", phase.to_string());
+    command.envs(envs.iter().map(|(k, v)| (k, v)));
+    debug!(
+        "run {phase} hook '{}' with env: {}",
+        script_file.display(),
+        envs.iter()
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+
+    let mut child = Command::from(command)
+        .current_dir(target)
+        .kill_on_drop(true)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("run {phase} hook {}", script_file.display()))?;
+
+    let outcome = {
+        let wait_for_exit = child.wait().fuse();
+        pin_mut!(wait_for_exit);
+        let wait_for_timeout = async {
+            match hook.timeout {
+                Some(timeout) => tokio::time::sleep(Duration::from_secs(timeout)).await,
+                None => future::pending().await,
+            }
+        }
+        .fuse();
+        pin_mut!(wait_for_timeout);
+        let wait_for_cancel = token.cancelled().fuse();
+        pin_mut!(wait_for_cancel);
+
+        select! {
+            result = wait_for_exit => Outcome::Exited(result),
+            _ = wait_for_timeout => Outcome::TimedOut(hook.timeout.unwrap_or_default()),
+            _ = wait_for_cancel => Outcome::Cancelled,
+        }
+    };
+
+    let result = match outcome {
+        Outcome::Exited(result) => result
+            .with_context(|| format!("wait for {phase} hook {}", script_file.display()))
+            .and_then(|status| {
+                if !status.success() {
+                    bail!(
+                        "{phase} hook {} failed with {status}",
+                        script_file.display()
+                    );
+                }
+                Ok(())
+            }),
+        Outcome::TimedOut(timeout) => {
+            let err = anyhow!(
+                "{phase} hook {} timed out after {timeout}s",
+                script_file.display()
+            );
+            match terminate(&mut child).await {
+                Ok(()) => Err(err),
+                Err(kill_err) => Err(err.context(format!("{kill_err:#}"))),
+            }
+        },
+        Outcome::Cancelled => {
+            terminate(&mut child).await?;
+            bail!("{phase} hook {} cancelled", script_file.display());
+        },
+    };
+    match result {
+        Err(err) if hook.allow_failure => {
+            warn!("ignore failure: {err:#}");
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::Hook;
+
+    #[test]
+    fn test_parse_shebang() {
+        assert_eq!(
+            parse_shebang("#! /bin/bash\necho hi\n"),
+            Some(("/bin/bash".to_owned(), vec![]))
+        );
+        assert_eq!(
+            parse_shebang("#!/usr/bin/env python3\n"),
+            Some(("/usr/bin/env".to_owned(), vec!["python3".to_owned()]))
+        );
+        assert_eq!(parse_shebang("echo hi\n"), None);
+        assert_eq!(parse_shebang("#!\n"), None);
+    }
+
+    #[cfg(target_family = "unix")]
+    fn sleeping_hooks(target: &Path, timeout: Option<u64>, allow_failure: bool) -> Hooks {
+        std::fs::write(target.join("sleep.sh"), "#!/bin/sh\nsleep 10\n").unwrap();
+        Hooks {
+            pre_up: Some(Hook {
+                path: PathBuf::from("sleep.sh"),
+                interpreter: None,
+                timeout,
+                allow_failure,
+                required: false,
+                container: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_run_hook_timeout() {
+        let target = tempfile::tempdir().unwrap();
+        let hooks = sleeping_hooks(target.path(), Some(1), false);
+        let started = std::time::Instant::now();
+        let result = run_hook(
+            target.path(),
+            &hooks,
+            Phase::PreUp,
+            &[],
+            CancellationToken::new(),
+        )
+        .await;
+        assert!(format!("{:#}", result.unwrap_err()).contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let hooks = sleeping_hooks(target.path(), Some(1), true);
+        run_hook(
+            target.path(),
+            &hooks,
+            Phase::PreUp,
+            &[],
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_run_hook_cancel() {
+        let target = tempfile::tempdir().unwrap();
+        // a cancel isn't a failure of the hook, allow_failure doesn't hide it
+        let hooks = sleeping_hooks(target.path(), None, true);
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+        let started = std::time::Instant::now();
+        let result = run_hook(target.path(), &hooks, Phase::PreUp, &[], token).await;
+        assert!(format!("{:#}", result.unwrap_err()).contains("cancelled"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
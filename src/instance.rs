@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+use tokio::fs;
+
+use crate::compose_helper::sanitize_project_name;
+use crate::hook::Action;
+use crate::manifest::Manifest;
+use crate::state::State;
+use crate::DEFAULT_TARGET_DIR;
+
+/// The dir holding the render dirs of the named instances of an app.
+pub(crate) const INSTANCES_DIR: &str = ".instances";
+
+/// Check that `name` can name an instance, it ends up in the compose project name.
+pub(crate) fn parse_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || sanitize_project_name(name) != name {
+        return Err("only a-z, 0-9, '-' and '_' are allowed".to_owned());
+    }
+    Ok(name.to_owned())
+}
+
+/// The render dir of the instance `name` of the app in `dir`.
+pub(crate) fn target_dir(dir: &Path, name: &str) -> PathBuf {
+    dir.join(INSTANCES_DIR).join(name)
+}
+
+/// The name of the instance rendered into `target`, `None` for any other target dir.
+pub(crate) fn name_of(target: &Path) -> Option<String> {
+    let parent = target.parent()?;
+    if parent.file_name()? != INSTANCES_DIR {
+        return None;
+    }
+    Some(target.file_name()?.to_string_lossy().to_string())
+}
+
+/// The render dirs of the default deployment and of every instance of the app in `dir`,
+/// by instance name.
+pub(crate) async fn targets(dir: &Path) -> Result<Vec<(Option<String>, PathBuf)>> {
+    let mut targets = Vec::new();
+    let default_target = dir.join(DEFAULT_TARGET_DIR);
+    if default_target.is_dir() {
+        targets.push((None, default_target));
+    }
+    let Ok(mut read_dir) = fs::read_dir(dir.join(INSTANCES_DIR)).await else {
+        return Ok(targets);
+    };
+    let mut instances = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await.context("get dir next entry")? {
+        if entry.file_type().await.context("get file type")?.is_dir() {
+            instances.push((
+                Some(entry.file_name().to_string_lossy().to_string()),
+                entry.path(),
+            ));
+        }
+    }
+    instances.sort_unstable();
+    targets.extend(instances);
+    Ok(targets)
+}
+
+fn is_port_free(ip: &str, port: u16) -> bool {
+    !matches!(TcpListener::bind((ip, port)), Err(err) if err.kind() == ErrorKind::AddrInUse)
+}
+
+/// Move the host ports of `manifest` to ones which no other deployment of the app in
+/// `dir` holds and which are free, so that the instance in `target` can run beside them.
+/// The ports recorded for the instance before are kept.
+pub(crate) async fn assign_ports(dir: &Path, target: &Path, manifest: &mut Manifest) -> Result<()> {
+    let recorded = State::load(target)
+        .await
+        .map(|v| v.ports)
+        .unwrap_or_default();
+    let target = target.canonicalize().context("get target abs path")?;
+
+    let mut taken = HashSet::new();
+    for (_, other) in targets(dir).await? {
+        if other.canonicalize().ok().as_ref() == Some(&target) {
+            continue;
+        }
+        if let Ok(state) = State::load(&other).await {
+            if state.action != Action::Down {
+                taken.extend(state.ports.into_values());
+            }
+        }
+    }
+
+    let mut names: Vec<_> = manifest.ports.keys().cloned().collect();
+    names.sort_unstable();
+    for name in names {
+        let port = manifest.ports.get_mut(&name).unwrap();
+        if let Some(recorded) = recorded.get(&name) {
+            port.port = *recorded;
+            taken.insert(*recorded);
+            continue;
+        }
+        let mut candidate = port.port;
+        while taken.contains(&candidate) || !is_port_free(&port.ip, candidate) {
+            candidate = candidate
+                .checked_add(1)
+                .with_context(|| format!("no free port left for {name}"))?;
+        }
+        if candidate != port.port {
+            info!("use port {candidate} for {name} instead of {}", port.port);
+        }
+        taken.insert(candidate);
+        port.port = candidate;
+    }
+    Ok(())
+}
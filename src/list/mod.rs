@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use tokio_util::sync::CancellationToken;
+
+use crate::registry;
+
+const HEADERS: [&str; 7] = [
+    "APP", "INSTANCE", "VERSION", "STATUS", "PROJECT", "PORTS", "TARGET",
+];
+
+/// Print the apps deployed by this user on the host, as a table or as JSON. Deployments
+/// whose target dir is gone are dropped from the registry first.
+pub(super) async fn list(json: bool, token: CancellationToken) -> Result<()> {
+    let entries = registry::prune().await.context("load registry")?;
+    if token.is_cancelled() {
+        return Ok(());
+    }
+    if json {
+        let content = serde_json::to_string_pretty(&entries).context("serialize registry")?;
+        println!("{content}");
+        return Ok(());
+    }
+
+    let rows: Vec<_> = entries
+        .into_iter()
+        .map(|entry| {
+            [
+                entry.app_name,
+                entry.instance.unwrap_or_else(|| "-".to_owned()),
+                entry.version,
+                entry.action.to_string(),
+                entry.project_name,
+                entry
+                    .ports
+                    .iter()
+                    .map(|(name, port)| format!("{name}={port}"))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                entry.target.display().to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let headers = HEADERS.map(str::to_owned);
+    for row in std::iter::once(&headers).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
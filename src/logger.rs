@@ -1,17 +1,68 @@
+use std::sync::RwLock;
+
 use anyhow::Result;
-use log::LevelFilter;
+use log::{LevelFilter, Record};
 use log4rs::append::console::{ConsoleAppender, Target};
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::{Encode, Write};
 use log4rs::Config;
 
+const SECRET_MASK: &str = "******";
+
+lazy_static::lazy_static! {
+    static ref SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+}
+
+/// Never print `secret` in any log message from now on.
+pub(crate) fn mask_secret<S: Into<String>>(secret: S) {
+    let secret = secret.into();
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.contains(&secret) {
+        secrets.push(secret);
+        // replace the longest secrets first so that no part of them survives
+        secrets.sort_unstable_by_key(|v| std::cmp::Reverse(v.len()));
+    }
+}
+
+/// An encoder which replaces every registered secret before encoding the record.
+#[derive(Debug)]
+struct MaskEncoder(PatternEncoder);
+
+impl Encode for MaskEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &Record) -> Result<()> {
+        let secrets = SECRETS.read().unwrap();
+        if secrets.is_empty() {
+            return self.0.encode(w, record);
+        }
+        let mut message = record.args().to_string();
+        for secret in secrets.iter() {
+            message = message.replace(secret.as_str(), SECRET_MASK);
+        }
+        self.0.encode(
+            w,
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .level(record.level())
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        )
+    }
+}
+
 fn build_logger_config(level_filter: LevelFilter, stderr: bool) -> Result<Config> {
     let mut root_builder = Root::builder();
     let mut config_builder = Config::builder();
     let console_pattern =
         PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S %Z)(local)} - {h({l})} - [{M}] - {m}{n}");
     let console = ConsoleAppender::builder()
-        .encoder(Box::new(console_pattern))
+        .encoder(Box::new(MaskEncoder(console_pattern)))
         .target(if stderr {
             Target::Stderr
         } else {
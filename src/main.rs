@@ -1,11 +1,25 @@
 #![feature(const_option_ext)]
 #![feature(path_file_prefix)]
 
+mod backup;
 mod compose_helper;
 mod down;
+mod drift;
+mod hook;
+mod instance;
+mod list;
 mod logger;
+mod manifest;
 mod new;
+mod registry;
+mod rollback;
+mod snapshot;
+mod state;
+mod status;
+mod target_dir;
+mod template_files;
 mod up;
+mod validate;
 mod version;
 
 use std::env::{self, current_exe};
@@ -14,7 +28,7 @@ use std::process::ExitCode;
 use std::time::Duration;
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use futures::future::Fuse;
 use futures::{pin_mut, select, FutureExt};
 use log::{debug, error, info, warn};
@@ -22,6 +36,7 @@ use logger::setup_logger_by_verbose;
 use tokio::signal;
 use tokio_util::sync::CancellationToken;
 
+use self::hook::Action;
 use self::version::{short_version, version};
 
 lazy_static::lazy_static! {
@@ -32,6 +47,7 @@ lazy_static::lazy_static! {
 }
 
 pub(crate) const MANIFEST_FILENAME: &str = "manifest.yaml";
+pub(crate) const DEFAULT_TARGET_DIR: &str = ".render";
 
 const SHORT_HEADER: &str = r#"
 ╔═╗╔═╗╦  ╦  ╦╔═╗
@@ -65,6 +81,26 @@ struct Opts {
     command: Command,
 }
 
+#[derive(Args)]
+struct TargetOpts {
+    /// Where is the APP render to default is .render
+    #[arg(short, long, default_value_os_t = PathBuf::from(DEFAULT_TARGET_DIR))]
+    target_dir: PathBuf,
+    /// Use the render dir of a named instance of the APP instead
+    #[arg(short, long, conflicts_with = "target_dir", value_parser = instance::parse_name)]
+    instance: Option<String>,
+}
+
+impl TargetOpts {
+    /// The render dir, the one of the instance of the app in `dir` if there is one.
+    fn path(&self, dir: &Path) -> PathBuf {
+        match &self.instance {
+            Some(name) => instance::target_dir(dir, name),
+            None => self.target_dir.clone(),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Command {
     /// Create a empty APP
@@ -74,19 +110,117 @@ enum Command {
     },
     /// Up like docker compose up
     Up {
-        /// Where is the APP render to default is .render
-        #[arg(short, long, default_value_os_t = PathBuf::from(".render"))]
-        target_dir: PathBuf,
+        #[command(flatten)]
+        target: TargetOpts,
         /// Just see the result not really run
         #[arg(long)]
         dry: bool,
+        /// Render even into a non-empty target dir not managed by collie
+        #[arg(long)]
+        force: bool,
+        /// Overwrite the files hand-edited in the target dir since the last render
+        #[arg(long)]
+        overwrite_drift: bool,
+        /// The compose project name, by default derived from the app_id
+        #[arg(short, long)]
+        project_name: Option<String>,
+    },
+    /// Upgrade a deployed APP to the current version
+    Upgrade {
+        #[command(flatten)]
+        target: TargetOpts,
+        /// Just see the result not really run
+        #[arg(long)]
+        dry: bool,
+        /// Render even into a non-empty target dir not managed by collie
+        #[arg(long)]
+        force: bool,
+        /// Overwrite the files hand-edited in the target dir since the last render
+        #[arg(long)]
+        overwrite_drift: bool,
+        /// Restore the previous deployment if the upgrade fails
+        #[arg(long)]
+        rollback_on_failure: bool,
+        /// Back up the data of the deployed APP before upgrading it
+        #[arg(long)]
+        backup: bool,
+        /// The compose project name, by default derived from the app_id
+        #[arg(short, long)]
+        project_name: Option<String>,
+    },
+    /// Archive the volumes and bind mounted data dirs of a deployed APP
+    Backup {
+        #[command(flatten)]
+        target: TargetOpts,
+        /// The compose project name, by default derived from the app_id
+        #[arg(short, long)]
+        project_name: Option<String>,
+        /// Where to put the archive, by default below the user data dir
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+    },
+    /// Put the data of a backup archive back into a deployed APP
+    Restore {
+        #[command(flatten)]
+        target: TargetOpts,
+        /// The compose project name, by default derived from the app_id
+        #[arg(short, long)]
+        project_name: Option<String>,
+        /// Restore even a backup of another APP
+        #[arg(long)]
+        force: bool,
+        /// The backup archive
+        archive: PathBuf,
+    },
+    /// Restore the deployment before the last up or upgrade
+    Rollback {
+        #[command(flatten)]
+        target: TargetOpts,
+        /// The compose project name, by default derived from the app_id
+        #[arg(short, long)]
+        project_name: Option<String>,
     },
     /// Down like docker compose down
     Down {
-        /// Where is the APP render to default is .render
-        #[arg(short, long, default_value_os_t = PathBuf::from(".render"))]
-        target_dir: PathBuf,
+        #[command(flatten)]
+        target: TargetOpts,
+        /// The compose project name, by default derived from the app_id
+        #[arg(short, long)]
+        project_name: Option<String>,
+        /// Remove the named volumes of the APP too
+        #[arg(long)]
+        volumes: bool,
+        /// Delete the bind mounted data dirs inside the render dir
+        #[arg(long)]
+        purge_data: bool,
+        /// Delete the render dir afterwards
+        #[arg(long)]
+        remove_render_dir: bool,
+        /// Don't ask before removing anything
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Show the containers of the APP like docker compose ps
+    Status {
+        #[command(flatten)]
+        target: TargetOpts,
+        /// The compose project name, by default derived from the app_id
+        #[arg(short, long)]
+        project_name: Option<String>,
+    },
+    /// List the files in the render dir which changed since the last render
+    Drift {
+        #[command(flatten)]
+        target: TargetOpts,
+    },
+    /// List the APPs deployed on this host
+    List {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
     },
+    /// Check the templates against the manifest without rendering them
+    Validate,
 }
 
 async fn deliver_command<P: AsRef<Path>>(
@@ -94,6 +228,7 @@ async fn deliver_command<P: AsRef<Path>>(
     command: Command,
     token: CancellationToken,
 ) -> ExitCode {
+    let dir = dir.as_ref();
     match command {
         Command::New {
             name,
@@ -112,27 +247,198 @@ async fn deliver_command<P: AsRef<Path>>(
             },
         },
         Command::Up {
-            target_dir,
+            target,
             dry,
-        } => match up::render_and_up(dir, target_dir, dry, token).await {
-            Ok(()) => {
-                println!("Up success.");
+            force,
+            overwrite_drift,
+            project_name,
+        } => {
+            let options = up::Options {
+                action: Action::Up,
+                dry,
+                force,
+                overwrite_drift,
+                rollback_on_failure: false,
+                backup: false,
+                project_name,
+                instance: target.instance.clone(),
+            };
+            match up::render_and_up(dir, target.path(dir), options, token).await {
+                Ok(()) => {
+                    println!("Up success.");
+                    ExitCode::SUCCESS
+                },
+                Err(err) => {
+                    error!("Up app: {err:#}");
+                    ExitCode::FAILURE
+                },
+            }
+        },
+        Command::Upgrade {
+            target,
+            dry,
+            force,
+            overwrite_drift,
+            rollback_on_failure,
+            backup,
+            project_name,
+        } => {
+            let options = up::Options {
+                action: Action::Upgrade,
+                dry,
+                force,
+                overwrite_drift,
+                rollback_on_failure,
+                backup,
+                project_name,
+                instance: target.instance.clone(),
+            };
+            match up::render_and_up(dir, target.path(dir), options, token).await {
+                Ok(()) => {
+                    println!("Upgrade success.");
+                    ExitCode::SUCCESS
+                },
+                Err(err) => {
+                    error!("Upgrade app: {err:#}");
+                    ExitCode::FAILURE
+                },
+            }
+        },
+        Command::Backup {
+            target,
+            project_name,
+            output_dir,
+        } => {
+            let result = backup::backup(
+                target.path(dir),
+                project_name.as_deref(),
+                target.instance.as_deref(),
+                output_dir.as_deref(),
+                token,
+            )
+            .await;
+            match result {
+                Ok(archive) => {
+                    println!("Backup to {} success.", archive.display());
+                    ExitCode::SUCCESS
+                },
+                Err(err) => {
+                    error!("Backup app: {err:#}");
+                    ExitCode::FAILURE
+                },
+            }
+        },
+        Command::Restore {
+            target,
+            project_name,
+            force,
+            archive,
+        } => {
+            let result = backup::restore(
+                target.path(dir),
+                &archive,
+                project_name.as_deref(),
+                target.instance.as_deref(),
+                force,
+                token,
+            )
+            .await;
+            match result {
+                Ok(version) => {
+                    println!("Restore backup of {version} success.");
+                    ExitCode::SUCCESS
+                },
+                Err(err) => {
+                    error!("Restore app: {err:#}");
+                    ExitCode::FAILURE
+                },
+            }
+        },
+        Command::Rollback {
+            target,
+            project_name,
+        } => match rollback::rollback(target.path(dir), project_name.as_deref(), token).await {
+            Ok(version) => {
+                println!("Rollback to {version} success.");
                 ExitCode::SUCCESS
             },
             Err(err) => {
-                error!("Up app: {err:#}");
+                error!("Rollback app: {err:#}");
                 ExitCode::FAILURE
             },
         },
         Command::Down {
-            target_dir,
-        } => match down::down(target_dir, token).await {
+            target,
+            project_name,
+            volumes,
+            purge_data,
+            remove_render_dir,
+            yes,
+        } => {
+            let options = down::Options {
+                project_name,
+                instance: target.instance.clone(),
+                volumes,
+                purge_data,
+                remove_render_dir,
+                yes,
+            };
+            match down::down(dir, target.path(dir), options, token).await {
+                Ok(()) => {
+                    println!("Down success.");
+                    ExitCode::SUCCESS
+                },
+                Err(err) => {
+                    error!("Down app: {err:#}");
+                    ExitCode::FAILURE
+                },
+            }
+        },
+        Command::Status {
+            target,
+            project_name,
+        } => match status::status(target.path(dir), project_name, target.instance, token).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                error!("Status app: {err:#}");
+                ExitCode::FAILURE
+            },
+        },
+        Command::Drift {
+            target,
+        } => match drift::drift(target.path(dir), token).await {
+            Ok(drifts) if drifts.is_empty() => {
+                println!("No drift.");
+                ExitCode::SUCCESS
+            },
+            Ok(drifts) => {
+                for drift in &drifts {
+                    println!("{drift}");
+                }
+                error!("{} file(s) drifted", drifts.len());
+                ExitCode::FAILURE
+            },
+            Err(err) => {
+                error!("Drift app: {err:#}");
+                ExitCode::FAILURE
+            },
+        },
+        Command::List {
+            json,
+        } => match list::list(json, token).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                error!("List app: {err:#}");
+                ExitCode::FAILURE
+            },
+        },
+        Command::Validate => match validate::validate(dir, token).await {
             Ok(()) => {
-                println!("Down success.");
+                println!("Validate success.");
                 ExitCode::SUCCESS
             },
             Err(err) => {
-                error!("Down app: {err:#}");
+                error!("Validate app: {err:#}");
                 ExitCode::FAILURE
             },
         },
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::hook::Phase;
+use crate::logger::mask_secret;
+use crate::{INIT_SCRIPT_PATH, MANIFEST_FILENAME, UNINSTALL_SCRIPT_PATH, UPGRADE_SCRIPT_PATH};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Metadata {
+    #[serde(default)]
+    pub(crate) app_id: String,
+    pub(crate) name: String,
+    pub(crate) desc: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Port {
+    pub(crate) ip: String,
+    pub(crate) port: u16,
+    pub(crate) desc: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Variable {
+    pub(crate) name: String,
+    pub(crate) desc: String,
+    pub(crate) value: String,
+    /// Secret values are masked in the logs.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) secret: bool,
+}
+
+/// How a hook gets into a container of a compose service.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ContainerMode {
+    /// Pipe the script into the running container of the service.
+    #[default]
+    Exec,
+    /// Start a one-shot container of the service with the scripts dir mounted.
+    Run,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct HookContainer {
+    /// The compose service whose container runs the script.
+    pub(crate) service: String,
+    #[serde(default)]
+    pub(crate) mode: ContainerMode,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Hook {
+    /// The script path relative to the app root.
+    pub(crate) path: PathBuf,
+    /// The program which runs the script like `bash`, `python3` or `pwsh`. Without it the
+    /// shebang on unix or the `.ps1`/`.cmd`/`.bat` extension on windows decides, else `sh`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) interpreter: Option<String>,
+    /// Kill the script after so many seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout: Option<u64>,
+    /// Only warn when the script fails.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) allow_failure: bool,
+    /// Fail instead of skipping the hook when the script is missing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) required: bool,
+    /// Run the script inside a container of the app instead of on the host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) container: Option<HookContainer>,
+}
+
+impl Hook {
+    fn from_path<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            interpreter: None,
+            timeout: None,
+            allow_failure: false,
+            required: false,
+            container: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Hooks {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pre_render: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) post_render: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pre_up: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) post_up: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pre_down: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) post_down: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pre_upgrade: Option<Hook>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) post_upgrade: Option<Hook>,
+}
+
+impl Hooks {
+    /// The fixed scripts of apps whose manifest has no `hooks` section.
+    pub(crate) fn legacy() -> Self {
+        Self {
+            pre_up: Some(Hook::from_path(&*INIT_SCRIPT_PATH)),
+            post_down: Some(Hook::from_path(&*UNINSTALL_SCRIPT_PATH)),
+            post_upgrade: Some(Hook::from_path(&*UPGRADE_SCRIPT_PATH)),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn get(&self, phase: Phase) -> Option<&Hook> {
+        match phase {
+            Phase::PreRender => self.pre_render.as_ref(),
+            Phase::PostRender => self.post_render.as_ref(),
+            Phase::PreUp => self.pre_up.as_ref(),
+            Phase::PostUp => self.post_up.as_ref(),
+            Phase::PreDown => self.pre_down.as_ref(),
+            Phase::PostDown => self.post_down.as_ref(),
+            Phase::PreUpgrade => self.pre_upgrade.as_ref(),
+            Phase::PostUpgrade => self.post_upgrade.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub(crate) metadata: Metadata,
+    pub(crate) templates: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) templates_exclude: Vec<String>,
+    pub(crate) ports: HashMap<String, Port>,
+    pub(crate) variables: HashMap<String, Variable>,
+    #[serde(default = "Hooks::legacy")]
+    pub(crate) hooks: Hooks,
+}
+
+impl Manifest {
+    /// Read and parse the manifest of the app in `dir`.
+    pub(crate) async fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let manifest_file_path = dir.as_ref().join(MANIFEST_FILENAME);
+        let manifest_content = fs::read_to_string(&manifest_file_path)
+            .await
+            .with_context(|| format!("read file: {}", manifest_file_path.display()))?;
+        let manifest: Self =
+            serde_yaml::from_str(&manifest_content).context("can't parse config")?;
+        // before any command gets to log them
+        for variable in manifest.variables.values().filter(|v| v.secret) {
+            mask_secret(variable.value.as_str());
+        }
+        Ok(manifest)
+    }
+}
@@ -8,6 +8,7 @@ use serde::Serialize;
 use tokio::fs;
 use tokio_util::sync::CancellationToken;
 
+use crate::up::helpers::register_helpers;
 #[cfg(target_family = "unix")]
 use crate::{INIT_SCRIPT_PATH, UNINSTALL_SCRIPT_PATH, UPGRADE_SCRIPT_PATH};
 
@@ -73,6 +74,7 @@ pub(super) async fn generate<P: AsRef<Path>>(
     // render tpl manifest
     let manifest_path = path.join("manifest.yaml");
     let mut handlebars = Handlebars::new();
+    register_helpers(&mut handlebars);
     let template_content = fs::read_to_string(&manifest_path)
         .await
         .context("read template content")?;
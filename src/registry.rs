@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::hook::Action;
+use crate::instance;
+use crate::state::State;
+use crate::target_dir::absolute;
+
+/// The file in the data dir listing the apps deployed by this user.
+const REGISTRY_FILENAME: &str = "registry.yaml";
+
+/// An app deployed into a target dir on this host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// The app dir it was rendered from, unknown if it was only seen by `down` or `rollback`.
+    pub(crate) workspace: Option<PathBuf>,
+    pub(crate) target: PathBuf,
+    pub(crate) instance: Option<String>,
+    pub(crate) app_id: String,
+    pub(crate) app_name: String,
+    pub(crate) version: String,
+    pub(crate) action: Action,
+    pub(crate) project_name: String,
+    #[serde(default)]
+    pub(crate) ports: BTreeMap<String, u16>,
+    /// Seconds since the unix epoch.
+    pub(crate) timestamp: u64,
+}
+
+/// The per-user data dir of the CLI, `$XDG_DATA_HOME/collie-app-cli` and the like.
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    #[cfg(target_family = "windows")]
+    let data_home = env::var_os("LOCALAPPDATA").map(PathBuf::from);
+
+    #[cfg(target_family = "unix")]
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|v| v.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|v| Path::new(&v).join(".local").join("share")));
+
+    data_home.map(|v| v.join("collie-app-cli"))
+}
+
+fn registry_file() -> Result<PathBuf> {
+    data_dir()
+        .map(|v| v.join(REGISTRY_FILENAME))
+        .ok_or_else(|| anyhow!("can't find the user data dir"))
+}
+
+/// The registered deployments, none if nothing was registered yet.
+pub(crate) async fn load() -> Result<Vec<Entry>> {
+    let registry_file = registry_file()?;
+    if !registry_file.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&registry_file)
+        .await
+        .with_context(|| format!("read {}", registry_file.display()))?;
+    serde_yaml::from_str(&content).with_context(|| format!("parse {}", registry_file.display()))
+}
+
+async fn save(entries: &[Entry]) -> Result<()> {
+    let registry_file = registry_file()?;
+    let data_dir = registry_file.parent().unwrap();
+    fs::create_dir_all(data_dir)
+        .await
+        .with_context(|| format!("create data dir: {}", data_dir.display()))?;
+    let content = serde_yaml::to_string(entries).context("serialize registry")?;
+    // another CLI may read it meanwhile, never let it see half of the file
+    let tmp_file = registry_file.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp_file, content)
+        .await
+        .with_context(|| format!("write {}", tmp_file.display()))?;
+    fs::rename(&tmp_file, &registry_file)
+        .await
+        .with_context(|| format!("write {}", registry_file.display()))
+}
+
+/// The registered deployments whose target dir still exists, the others are dropped.
+pub(crate) async fn prune() -> Result<Vec<Entry>> {
+    let mut entries = load().await?;
+    let count = entries.len();
+    entries.retain(|v| v.target.is_dir());
+    if entries.len() != count {
+        debug!("prune {} gone deployment(s)", count - entries.len());
+        save(&entries).await?;
+    }
+    Ok(entries)
+}
+
+/// The registered deployment in `target`, which may be gone already.
+pub(crate) async fn find(target: &Path) -> Option<Entry> {
+    let target = target.canonicalize().or_else(|_| absolute(target)).ok()?;
+    load().await.ok()?.into_iter().find(|v| v.target == target)
+}
+
+async fn try_record(workspace: Option<&Path>, target: &Path, state: &State) -> Result<()> {
+    let target = target.canonicalize().context("get target abs path")?;
+    let workspace = match workspace {
+        Some(v) => Some(v.canonicalize().context("get app abs path")?),
+        None => None,
+    };
+    let mut entries = load().await?;
+    let previous = entries
+        .iter()
+        .position(|v| v.target == target)
+        .map(|i| entries.remove(i));
+    entries.push(Entry {
+        workspace: workspace.or_else(|| previous.and_then(|v| v.workspace)),
+        instance: instance::name_of(&target),
+        target,
+        app_id: state.app_id.clone(),
+        app_name: state.app_name.clone(),
+        version: state.version.clone(),
+        action: state.action,
+        project_name: state.project_name.clone(),
+        ports: state.ports.clone(),
+        timestamp: state.timestamp,
+    });
+    save(&entries).await
+}
+
+/// Register the deployment `state` in `target` rendered from `workspace`. It's only the
+/// inventory for `list`, so failing is just a warning.
+pub(crate) async fn record(workspace: Option<&Path>, target: &Path, state: &State) {
+    if let Err(err) = try_record(workspace, target, state).await {
+        warn!("record deployment in registry: {err:#}");
+    }
+}
@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use tokio_util::sync::CancellationToken;
+
+use crate::compose_helper::{compose, resolve_project_name};
+use crate::state::State;
+use crate::{registry, snapshot};
+
+/// Restore the deployment in `target` from before the last up or upgrade and bring it up
+/// again as the compose project `project_name`, by default the recorded one. Returns the
+/// restored version.
+pub(crate) async fn rollback<T: AsRef<Path>>(
+    target: T,
+    project_name: Option<&str>,
+    token: CancellationToken,
+) -> Result<String> {
+    let target = target.as_ref();
+
+    if !target.exists() {
+        bail!("targe not exist");
+    }
+
+    snapshot::restore(target)
+        .await
+        .context("restore previous deployment")?;
+    let state = State::load(target).await?;
+    info!("restored {} {}", state.app_name, state.version);
+
+    let project = resolve_project_name(project_name, None, target, None).await?;
+    compose(token, &target, &project, ["up", "-d", "--remove-orphans"])
+        .await
+        .context("run 'docker[.exe] compose up -d --remove-orphans'")?;
+    snapshot::discard(target).await?;
+    registry::record(None, target, &state).await;
+    Ok(state.version)
+}
@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tokio::fs;
+
+use crate::hook::ENV_FILENAME;
+use crate::state::STATE_FILENAME;
+use crate::up::sync::{create_link, read_synced, remove_stale, RENDERED_FILENAME, SYNCED_FILENAME};
+
+/// The dir in the target keeping the files of the deployment before the last up or upgrade.
+pub(crate) const SNAPSHOT_DIR: &str = ".collie.snapshot";
+
+/// Marks a target whose last up or upgrade didn't finish, so its files aren't worth a
+/// snapshot.
+const PENDING_FILENAME: &str = ".collie.pending";
+
+/// The files of the tool itself which belong to a deployment besides the synced ones.
+const META_FILENAMES: [&str; 4] = [
+    STATE_FILENAME,
+    SYNCED_FILENAME,
+    RENDERED_FILENAME,
+    ENV_FILENAME,
+];
+
+/// Copy the file or symlink `src` to `dst`, replacing what is there.
+async fn copy_entry(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(src)
+        .await
+        .with_context(|| format!("get metadata: {}", src.display()))?;
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("create dir: {}", parent.display()))?;
+    }
+    if fs::symlink_metadata(dst)
+        .await
+        .map_or(false, |v| !v.is_dir())
+    {
+        fs::remove_file(dst)
+            .await
+            .with_context(|| format!("remove file: {}", dst.display()))?;
+    }
+    if metadata.is_symlink() {
+        let link_target = fs::read_link(src)
+            .await
+            .with_context(|| format!("read symlink: {}", src.display()))?;
+        let resolved = src.parent().unwrap_or(src).join(&link_target);
+        create_link(&link_target, dst, &resolved)
+            .await
+            .with_context(|| format!("create symlink: {}", dst.display()))?;
+    } else {
+        fs::copy(src, dst)
+            .await
+            .with_context(|| format!("copy file: {}", src.display()))?;
+    }
+    Ok(())
+}
+
+/// Keep the files of the deployment in `target` in its snapshot dir, replacing the
+/// previous snapshot, and mark the target pending until [`settle`]. While the last up or
+/// upgrade hasn't settled the previous snapshot is kept instead, it's the last good one.
+/// Returns whether there is a snapshot to roll back to.
+pub(crate) async fn take(target: &Path) -> Result<bool> {
+    let snapshot = target.join(SNAPSHOT_DIR);
+    let pending = target.join(PENDING_FILENAME);
+    if pending.is_file() {
+        return Ok(snapshot.join(STATE_FILENAME).is_file());
+    }
+    if !target.join(STATE_FILENAME).is_file() {
+        return Ok(false);
+    }
+    if snapshot.exists() {
+        fs::remove_dir_all(&snapshot)
+            .await
+            .context("remove previous snapshot")?;
+    }
+    let synced = read_synced(target).await;
+    for name in synced.iter().map(String::as_str).chain(META_FILENAMES) {
+        let src = target.join(name);
+        if fs::symlink_metadata(&src).await.is_ok() {
+            copy_entry(&src, &snapshot.join(name)).await?;
+        }
+    }
+    fs::write(&pending, "")
+        .await
+        .with_context(|| format!("write {}", pending.display()))?;
+    Ok(true)
+}
+
+/// Drop the pending mark of `target` after its up or upgrade succeeded.
+pub(crate) async fn settle(target: &Path) -> Result<()> {
+    let pending = target.join(PENDING_FILENAME);
+    if !pending.exists() {
+        return Ok(());
+    }
+    fs::remove_file(&pending)
+        .await
+        .with_context(|| format!("remove {}", pending.display()))
+}
+
+/// Put the files of the snapshot back into `target`. Files synced since are removed,
+/// unless they sit in a bind mount. The snapshot stays until [`discard`], so a rollback
+/// that failed to come up can be retried.
+pub(crate) async fn restore(target: &Path) -> Result<()> {
+    let snapshot = target.join(SNAPSHOT_DIR);
+    if !snapshot.join(STATE_FILENAME).is_file() {
+        bail!(
+            "no previous deployment to roll back to in {}",
+            target.display()
+        );
+    }
+    let previous = read_synced(&snapshot).await;
+    remove_stale(target, read_synced(target).await.difference(&previous)).await?;
+    for name in previous.iter().map(String::as_str).chain(META_FILENAMES) {
+        let src = snapshot.join(name);
+        if fs::symlink_metadata(&src).await.is_ok() {
+            copy_entry(&src, &target.join(name)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop the snapshot of `target` and its pending mark once the restored deployment is up.
+pub(crate) async fn discard(target: &Path) -> Result<()> {
+    let snapshot = target.join(SNAPSHOT_DIR);
+    if snapshot.exists() {
+        fs::remove_dir_all(&snapshot)
+            .await
+            .context("remove snapshot")?;
+    }
+    settle(target).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_take_keeps_last_good_snapshot() {
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        std::fs::write(target.join(SYNCED_FILENAME), "app.conf\n").unwrap();
+        std::fs::write(target.join(STATE_FILENAME), "good").unwrap();
+        std::fs::write(target.join("app.conf"), "good").unwrap();
+        assert!(take(target).await.unwrap());
+
+        // the deploy failed half way, a retry must not snapshot its files
+        std::fs::write(target.join("app.conf"), "broken").unwrap();
+        assert!(take(target).await.unwrap());
+        let snapshotted = target.join(SNAPSHOT_DIR).join("app.conf");
+        assert_eq!(std::fs::read_to_string(&snapshotted).unwrap(), "good");
+
+        settle(target).await.unwrap();
+        assert!(take(target).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&snapshotted).unwrap(), "broken");
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::hook::{write_private_file, Action};
+use crate::manifest::Manifest;
+use crate::up::sync::{data_dirs, read_synced};
+use crate::version::short_version;
+
+/// The file in the target dir recording what was deployed there.
+pub(crate) const STATE_FILENAME: &str = ".collie.state.yaml";
+
+/// The hex encoded sha256 of the content of `path`.
+pub(crate) async fn hash_file(path: &Path) -> Result<String> {
+    let content = fs::read(path)
+        .await
+        .with_context(|| format!("read file: {}", path.display()))?;
+    Ok(format!("{:x}", Sha256::digest(content)))
+}
+
+/// A salted digest of the secret `value`, `sha256:<salt>:<hash>`, which neither gives the
+/// value away nor whether two secrets are the same.
+fn secret_digest(value: &str) -> String {
+    let salt = format!("{:032x}", rand::thread_rng().gen::<u128>());
+    let hash = Sha256::new()
+        .chain_update(&salt)
+        .chain_update(value)
+        .finalize();
+    format!("sha256:{salt}:{hash:x}")
+}
+
+/// The digest of the file or symlink `name` in `target`, `None` if it's gone.
+async fn digest(target: &Path, name: &str) -> Result<Option<String>> {
+    let path = target.join(name);
+    let Ok(metadata) = fs::symlink_metadata(&path).await else {
+        return Ok(None);
+    };
+    let digest = if metadata.is_symlink() {
+        let link_target = fs::read_link(&path)
+            .await
+            .with_context(|| format!("read symlink: {name}"))?;
+        format!("link:{}", link_target.display())
+    } else {
+        hash_file(&path).await?
+    };
+    Ok(Some(digest))
+}
+
+/// The digests of the files synced into `target`, as they are now.
+pub(crate) async fn rendered_files(target: &Path) -> Result<BTreeMap<String, String>> {
+    let mut files = BTreeMap::new();
+    for name in read_synced(target).await {
+        if let Some(digest) = digest(target, &name).await? {
+            files.insert(name, digest);
+        }
+    }
+    Ok(files)
+}
+
+/// A recorded file which doesn't look like it was rendered anymore.
+#[derive(Debug)]
+pub(crate) enum Drift {
+    Modified(String),
+    Missing(String),
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Modified(name) => write!(f, "modified: {name}"),
+            Drift::Missing(name) => write!(f, "missing:  {name}"),
+        }
+    }
+}
+
+/// What the last successful up, upgrade or down left in a target dir.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct State {
+    pub(crate) action: Action,
+    pub(crate) app_id: String,
+    pub(crate) app_name: String,
+    pub(crate) version: String,
+    pub(crate) project_name: String,
+    /// The host ports the app was deployed with.
+    #[serde(default)]
+    pub(crate) ports: BTreeMap<String, u16>,
+    /// The values of the manifest variables, secret ones only as a salted digest.
+    pub(crate) variables: BTreeMap<String, String>,
+    /// The sha256 of every file synced into the target after rendering, symlinks as
+    /// `link:<path>`.
+    pub(crate) files: BTreeMap<String, String>,
+    /// Seconds since the unix epoch.
+    pub(crate) timestamp: u64,
+    pub(crate) cli_version: String,
+}
+
+impl State {
+    /// Record `action` of the app described by `manifest` which is deployed in `target` as
+    /// the compose project `project_name`.
+    pub(crate) async fn new(
+        action: Action,
+        target: &Path,
+        manifest: &Manifest,
+        project_name: &str,
+    ) -> Result<Self> {
+        let variables = manifest
+            .variables
+            .iter()
+            .map(|(name, variable)| {
+                let value = if variable.secret {
+                    secret_digest(&variable.value)
+                } else {
+                    variable.value.clone()
+                };
+                (name.clone(), value)
+            })
+            .collect();
+
+        let files = rendered_files(target).await?;
+
+        Ok(Self {
+            action,
+            app_id: manifest.metadata.app_id.clone(),
+            app_name: manifest.metadata.name.clone(),
+            version: manifest.metadata.version.clone(),
+            project_name: project_name.to_owned(),
+            ports: manifest
+                .ports
+                .iter()
+                .map(|(name, port)| (name.clone(), port.port))
+                .collect(),
+            variables,
+            files,
+            timestamp: 0,
+            cli_version: String::new(),
+        })
+    }
+
+    /// The recorded files which were changed or removed in `target` since. Files in bind
+    /// mounted data dirs are left out, the containers may well change them, while a
+    /// bind mounted file is still checked.
+    pub(crate) async fn drift(&self, target: &Path) -> Result<Vec<Drift>> {
+        let target = target.canonicalize().context("get target abs path")?;
+        let data_dirs = data_dirs(&target).await?;
+        let mut drifts = Vec::new();
+        for (name, recorded) in &self.files {
+            let path = target.join(name);
+            if data_dirs.iter().any(|v| path.starts_with(v)) {
+                continue;
+            }
+            match digest(&target, name).await? {
+                None => drifts.push(Drift::Missing(name.clone())),
+                Some(digest) if digest != *recorded => drifts.push(Drift::Modified(name.clone())),
+                Some(_) => {},
+            }
+        }
+        Ok(drifts)
+    }
+
+    pub(crate) async fn load(target: &Path) -> Result<Self> {
+        let state_file = target.join(STATE_FILENAME);
+        let content = fs::read_to_string(&state_file)
+            .await
+            .with_context(|| format!("read {}", state_file.display()))?;
+        serde_yaml::from_str(&content).with_context(|| format!("parse {}", state_file.display()))
+    }
+
+    /// Stamp the state with the current time and CLI version and write it to `target`.
+    pub(crate) async fn save(&mut self, target: &Path) -> Result<()> {
+        self.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|v| v.as_secs())
+            .unwrap_or_default();
+        self.cli_version = short_version().to_owned();
+
+        let state_file = target.join(STATE_FILENAME);
+        let content = serde_yaml::to_string(self).context("serialize state")?;
+        write_private_file(&state_file, &content).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A state which recorded `files` in `target` as they are now.
+    async fn recorded(target: &Path, files: &[(&str, &str)]) -> State {
+        let mut synced = String::new();
+        for (name, content) in files {
+            let path = target.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+            synced.push_str(&format!("{name}\n"));
+        }
+        std::fs::write(target.join(".collie.files"), synced).unwrap();
+        State {
+            action: Action::Up,
+            app_id: String::new(),
+            app_name: String::new(),
+            version: String::new(),
+            project_name: String::new(),
+            ports: BTreeMap::new(),
+            variables: BTreeMap::new(),
+            files: rendered_files(target).await.unwrap(),
+            timestamp: 0,
+            cli_version: String::new(),
+        }
+    }
+
+    async fn drifts(state: &State, target: &Path) -> Vec<String> {
+        let drifts = state.drift(target).await.unwrap();
+        drifts.iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn test_drift_modified() {
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        let state = recorded(target, &[("a.conf", "a"), ("b.conf", "b")]).await;
+        assert!(drifts(&state, target).await.is_empty());
+
+        std::fs::write(target.join("b.conf"), "edited").unwrap();
+        assert_eq!(drifts(&state, target).await, ["modified: b.conf"]);
+    }
+
+    #[tokio::test]
+    async fn test_drift_missing() {
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        let state = recorded(target, &[("a.conf", "a"), ("c.conf", "c")]).await;
+
+        std::fs::remove_file(target.join("c.conf")).unwrap();
+        assert_eq!(drifts(&state, target).await, ["missing:  c.conf"]);
+    }
+
+    #[tokio::test]
+    async fn test_drift_bind_mounts() {
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        std::fs::write(
+            target.join("docker-compose.yaml"),
+            "services:\n  redis:\n    volumes:\n      - ./data:/data\n      - ./config/redis.conf:/etc/redis.conf\n",
+        )
+        .unwrap();
+        let state = recorded(target, &[("config/redis.conf", "a"), ("data/.gitkeep", "")]).await;
+        std::fs::write(target.join(".collie.rendered"), "config/redis.conf\n").unwrap();
+
+        // the containers own the data dir, a mounted file is still rendered
+        std::fs::write(target.join("data").join(".gitkeep"), "runtime").unwrap();
+        std::fs::write(target.join("config").join("redis.conf"), "hand-edited").unwrap();
+        assert_eq!(
+            drifts(&state, target).await,
+            ["modified: config/redis.conf"]
+        );
+    }
+
+    #[test]
+    fn test_secret_digest() {
+        let digest = secret_digest("secret");
+        assert!(digest.starts_with("sha256:"));
+        assert!(!digest.contains("secret"));
+        // salted, the same secret never gives the same digest
+        assert_ne!(digest, secret_digest("secret"));
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_save_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let target = tempfile::tempdir().unwrap();
+        let target = target.path();
+        let mut state = recorded(target, &[]).await;
+        state.save(target).await.unwrap();
+        let metadata = std::fs::metadata(target.join(STATE_FILENAME)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+}
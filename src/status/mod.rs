@@ -0,0 +1,42 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tokio_util::sync::CancellationToken;
+
+use crate::compose_helper::{compose, resolve_project_name};
+use crate::manifest::Manifest;
+use crate::state::State;
+
+/// Print what is deployed in `target` and its containers like `docker compose ps`.
+pub(super) async fn status<T: AsRef<Path>>(
+    target: T,
+    project_name: Option<String>,
+    instance: Option<String>,
+    token: CancellationToken,
+) -> Result<()> {
+    let target = target.as_ref();
+
+    if !target.exists() {
+        bail!("targe not exist");
+    }
+
+    let manifest = Manifest::from_dir(target).await.ok();
+    let project = resolve_project_name(
+        project_name.as_deref(),
+        instance.as_deref(),
+        target,
+        manifest.as_ref(),
+    )
+    .await?;
+    match State::load(target).await {
+        Ok(state) => println!(
+            "{} {} ({}), project {project}",
+            state.app_name, state.version, state.action
+        ),
+        Err(_) => println!("no deployment recorded, project {project}"),
+    }
+
+    compose(token, &target, &project, ["ps"])
+        .await
+        .context("run 'docker[.exe] compose ps'")
+}
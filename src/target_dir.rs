@@ -0,0 +1,208 @@
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use tokio::fs;
+
+use crate::version::short_version;
+use crate::DEFAULT_TARGET_DIR;
+
+/// Marks a dir as rendered by this tool, so that it can be synced into and cleaned up.
+pub(crate) const MARKER_FILENAME: &str = ".collie-target";
+
+/// `path` made absolute against the current dir without touching the file system.
+pub(crate) fn absolute(path: &Path) -> Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().context("get current dir")?.join(path)
+    };
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+fn home_dir() -> Option<PathBuf> {
+    #[cfg(target_family = "windows")]
+    let home = env::var_os("USERPROFILE");
+
+    #[cfg(target_family = "unix")]
+    let home = env::var_os("HOME");
+
+    home.map(PathBuf::from).and_then(|v| v.canonicalize().ok())
+}
+
+/// Why using a target dir could destroy something.
+#[derive(Debug)]
+enum Danger {
+    /// Never used as a target, not even with `--force`.
+    Fatal(String),
+    /// Used with `--force` only.
+    Overridable(String),
+}
+
+/// Why rendering the app in `dir` into `target` could destroy something, if it could.
+async fn check(dir: &Path, target: &Path) -> Result<Option<Danger>> {
+    let dir = dir.canonicalize().context("get app abs path")?;
+    let target = match target.canonicalize() {
+        Ok(v) => v,
+        // a dir which doesn't exist yet is created and marked by us
+        Err(_) => return Ok(None),
+    };
+
+    if target == dir {
+        return Ok(Some(Danger::Fatal("it is the app dir itself".to_owned())));
+    }
+    if dir.starts_with(&target) {
+        return Ok(Some(Danger::Fatal("it contains the app dir".to_owned())));
+    }
+    if home_dir().map_or(false, |home| home == target) {
+        return Ok(Some(Danger::Fatal("it is your home dir".to_owned())));
+    }
+    if !target.is_dir() {
+        return Ok(Some(Danger::Fatal("it is not a dir".to_owned())));
+    }
+    // the default render dir predates the marker
+    if target.join(MARKER_FILENAME).is_file() || target == dir.join(DEFAULT_TARGET_DIR) {
+        return Ok(None);
+    }
+    let mut read_dir = fs::read_dir(&target)
+        .await
+        .with_context(|| format!("read dir: {}", target.display()))?;
+    if read_dir
+        .next_entry()
+        .await
+        .context("get dir next entry")?
+        .is_some()
+    {
+        return Ok(Some(Danger::Overridable(format!(
+            "it is not empty and not managed by collie (no {MARKER_FILENAME} in it)"
+        ))));
+    }
+    Ok(None)
+}
+
+/// Refuse to render the app in `dir` into `target` when `target` is the app dir, one of
+/// its ancestors, the home dir or a non-empty dir without the marker. With `force` the
+/// latter is only a warning.
+pub(crate) async fn guard(dir: &Path, target: &Path, force: bool) -> Result<()> {
+    let display = absolute(target)?;
+    match check(dir, target).await? {
+        None => Ok(()),
+        Some(Danger::Fatal(reason)) => {
+            bail!("refuse to use target dir {}: {reason}", display.display())
+        },
+        Some(Danger::Overridable(reason)) if force => {
+            warn!("use target dir {} anyway: {reason}", display.display());
+            Ok(())
+        },
+        Some(Danger::Overridable(reason)) => bail!(
+            "refuse to use target dir {}: {reason}, pass --force if you are sure",
+            display.display()
+        ),
+    }
+}
+
+/// Refuse to delete `target` of the app in `dir` unless it is a render dir managed by
+/// collie, whatever marker ended up in it.
+pub(crate) async fn guard_removal(dir: &Path, target: &Path) -> Result<()> {
+    let reason = match check(dir, target).await? {
+        None => return Ok(()),
+        Some(Danger::Fatal(reason) | Danger::Overridable(reason)) => reason,
+    };
+    bail!("refuse to remove {}: {reason}", absolute(target)?.display());
+}
+
+/// Whether `target` was rendered by this tool.
+pub(crate) fn is_marked(target: &Path) -> bool {
+    target.join(MARKER_FILENAME).is_file()
+}
+
+/// Put the marker into `target`, which has to exist.
+pub(crate) async fn mark(target: &Path) -> Result<()> {
+    if is_marked(target) {
+        return Ok(());
+    }
+    let marker = target.join(MARKER_FILENAME);
+    fs::write(
+        &marker,
+        format!("rendered by collie-app-cli {}\n", short_version()),
+    )
+    .await
+    .with_context(|| format!("write {}", marker.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An app dir and an empty sibling dir below a temp root, with their paths.
+    fn fixture() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let app = root.path().join("app");
+        let other = root.path().join("other");
+        std::fs::create_dir_all(&app).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        (root, app, other)
+    }
+
+    #[tokio::test]
+    async fn test_check_fatal() {
+        let (root, app, _) = fixture();
+        for target in [&app, root.path(), Path::new("/")] {
+            assert!(
+                matches!(check(&app, target).await.unwrap(), Some(Danger::Fatal(_))),
+                "{}",
+                target.display()
+            );
+            assert!(guard(&app, target, true).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_safe() {
+        let (_root, app, other) = fixture();
+        assert!(check(&app, &app.join(DEFAULT_TARGET_DIR))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(check(&app, &other).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_check_unmarked() {
+        let (_root, app, other) = fixture();
+        std::fs::write(other.join("notes.txt"), "mine").unwrap();
+        assert!(matches!(
+            check(&app, &other).await.unwrap(),
+            Some(Danger::Overridable(_))
+        ));
+        assert!(guard(&app, &other, false).await.is_err());
+        assert!(guard(&app, &other, true).await.is_ok());
+
+        mark(&other).await.unwrap();
+        assert!(check(&app, &other).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_guard_removal() {
+        let (_root, app, other) = fixture();
+        std::fs::write(other.join("notes.txt"), "mine").unwrap();
+        assert!(guard_removal(&app, &other).await.is_err());
+        mark(&other).await.unwrap();
+        assert!(guard_removal(&app, &other).await.is_ok());
+
+        // a marker doesn't make the app dir removable
+        mark(&app).await.unwrap();
+        assert!(guard_removal(&app, &app).await.is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use async_recursion::async_recursion;
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use tokio::fs;
+
+const GLOB_META_CHARS: [char; 4] = ['*', '?', '[', '{'];
+
+fn build_glob(pattern: &str) -> Result<GlobMatcher> {
+    Ok(GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("illegal glob pattern: {pattern}"))?
+        .compile_matcher())
+}
+
+fn build_excludes(excludes: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in excludes {
+        let pattern = pattern.trim_end_matches('/');
+        // an excluded directory excludes everything below it
+        for pattern in [pattern.to_owned(), format!("{pattern}/**")] {
+            builder.add(
+                GlobBuilder::new(&pattern)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("illegal exclude pattern: {pattern}"))?,
+            );
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Turn a path relative to the app root into a template name.
+pub(crate) fn to_template_name(rel_path: &Path) -> String {
+    rel_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[async_recursion]
+async fn list_files(
+    root: &Path,
+    rel_dir: PathBuf,
+    skip: &[PathBuf],
+    ignore: &Gitignore,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let dir = root.join(&rel_dir);
+    let mut read_dir = fs::read_dir(&dir)
+        .await
+        .with_context(|| format!("read dir: {}", dir.display()))?;
+    while let Some(entry) = read_dir.next_entry().await.context("get dir next entry")? {
+        let rel_path = rel_dir.join(entry.file_name());
+        if skip.contains(&rel_path) {
+            continue;
+        }
+        let file_type = entry.file_type().await.context("get file type")?;
+        if ignore.matched(&rel_path, file_type.is_dir()).is_ignore() {
+            continue;
+        }
+        if file_type.is_dir() {
+            list_files(root, rel_path, skip, ignore, files).await?;
+        } else if file_type.is_file() {
+            files.push(rel_path);
+        }
+    }
+    Ok(())
+}
+
+async fn is_text_file(path: &Path) -> Result<bool> {
+    let content = fs::read(path)
+        .await
+        .with_context(|| format!("read file: {}", path.display()))?;
+    Ok(!content.contains(&0) && std::str::from_utf8(&content).is_ok())
+}
+
+/// Expand the `templates` entries of the manifest into the template files below
+/// `root`. An entry is either a file, a directory (every file below it) or a
+/// glob pattern like `config/**/*.conf`. Files matching one of `excludes` are
+/// dropped and paths in `skip` (relative to `root`) are never visited, nor what
+/// `ignore` matches, which isn't synced into the target.
+///
+/// Every entry has to be a relative path inside `root`, match at least one file and
+/// every matched file has to be a text file.
+pub(crate) async fn expand(
+    root: &Path,
+    templates: &[String],
+    excludes: &[String],
+    skip: &[PathBuf],
+    ignore: &Gitignore,
+) -> Result<Vec<String>> {
+    let excludes = build_excludes(excludes)?;
+    let mut all_files = None;
+    let mut matched = BTreeSet::new();
+    let mut problems = Vec::new();
+
+    for raw_entry in templates {
+        // `./config` is `config`, but nothing may lead out of the app
+        let rel_path: PathBuf = Path::new(raw_entry)
+            .components()
+            .filter(|v| *v != Component::CurDir)
+            .collect();
+        if !rel_path
+            .components()
+            .all(|v| matches!(v, Component::Normal(_)))
+        {
+            problems.push(format!(
+                "'{raw_entry}' is not a relative path inside the app"
+            ));
+            continue;
+        }
+        let entry = to_template_name(&rel_path);
+        let entry_path = root.join(&rel_path);
+        let mut candidates = Vec::new();
+        if !entry.is_empty()
+            && ignore
+                .matched_path_or_any_parents(&rel_path, entry_path.is_dir())
+                .is_ignore()
+        {
+            // matches no file, reported below
+        } else if entry_path.is_dir() {
+            list_files(root, rel_path, skip, ignore, &mut candidates).await?;
+        } else if entry.contains(GLOB_META_CHARS) {
+            if all_files.is_none() {
+                let mut files = Vec::new();
+                list_files(root, PathBuf::new(), skip, ignore, &mut files).await?;
+                all_files = Some(files);
+            }
+            let matcher = build_glob(&entry)?;
+            candidates.extend(
+                all_files
+                    .iter()
+                    .flatten()
+                    .filter(|rel_path| matcher.is_match(to_template_name(rel_path)))
+                    .cloned(),
+            );
+        } else if entry_path.is_file() {
+            candidates.push(rel_path);
+        }
+
+        let names: Vec<_> = candidates
+            .iter()
+            .map(|rel_path| to_template_name(rel_path))
+            .filter(|name| !excludes.is_match(name))
+            .collect();
+        if names.is_empty() {
+            problems.push(format!("'{raw_entry}' doesn't match any file"));
+        }
+        matched.extend(names);
+    }
+
+    for name in &matched {
+        if !is_text_file(&root.join(name)).await? {
+            problems.push(format!("'{name}' is not a text file"));
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!("illegal templates: {}", problems.join("; "));
+    }
+    Ok(matched.into_iter().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An app root holding `files`, each with some text.
+    fn fixture(files: &[&str]) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = root.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "text").unwrap();
+        }
+        root
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn test_expand_glob() {
+        let root = fixture(&["a.conf", "config/b.conf", "config/c/d.conf", "config/e.txt"]);
+        let templates = expand(
+            root.path(),
+            &strings(&["config/**/*.conf"]),
+            &[],
+            &[],
+            &Gitignore::empty(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(templates, ["config/b.conf", "config/c/d.conf"]);
+
+        // `*` doesn't cross dirs
+        let templates = expand(
+            root.path(),
+            &strings(&["*.conf"]),
+            &[],
+            &[],
+            &Gitignore::empty(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(templates, ["a.conf"]);
+    }
+
+    #[tokio::test]
+    async fn test_expand_dir_and_excludes() {
+        let root = fixture(&["config/a.conf", "config/skel/b.conf", "config/c.bak"]);
+        let templates = expand(
+            root.path(),
+            &strings(&["config/"]),
+            &strings(&["config/skel/", "**/*.bak"]),
+            &[],
+            &Gitignore::empty(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(templates, ["config/a.conf"]);
+    }
+
+    #[tokio::test]
+    async fn test_expand_skip() {
+        let root = fixture(&["a.conf", ".render/a.conf"]);
+        let templates = expand(
+            root.path(),
+            &strings(&["**/*.conf"]),
+            &[],
+            &[PathBuf::from(".render")],
+            &Gitignore::empty(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(templates, ["a.conf"]);
+    }
+
+    #[tokio::test]
+    async fn test_expand_current_dir() {
+        let root = fixture(&["config/a.conf", "config/b.conf"]);
+        let templates = expand(
+            root.path(),
+            &strings(&["./config/a.conf", "./config/*.conf"]),
+            &[],
+            &[],
+            &Gitignore::empty(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(templates, ["config/a.conf", "config/b.conf"]);
+    }
+
+    #[tokio::test]
+    async fn test_expand_ignore() {
+        let root = fixture(&["a.conf", "logs/b.conf", ".render/c.conf"]);
+        let mut builder = ignore::gitignore::GitignoreBuilder::new(root.path());
+        builder.add_line(None, "logs/").unwrap();
+        builder.add_line(None, ".render").unwrap();
+        let ignore = builder.build().unwrap();
+        let templates = expand(root.path(), &strings(&["**/*.conf"]), &[], &[], &ignore)
+            .await
+            .unwrap();
+        assert_eq!(templates, ["a.conf"]);
+        assert!(
+            expand(root.path(), &strings(&["logs/b.conf"]), &[], &[], &ignore)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expand_problems() {
+        let root = fixture(&["a.conf"]);
+        std::fs::write(root.path().join("b.bin"), [0u8, 1]).unwrap();
+        for entry in [
+            "missing.conf",
+            "b.bin",
+            "../a.conf",
+            "/etc/passwd",
+            "./../a.conf",
+        ] {
+            let result = expand(
+                root.path(),
+                &strings(&[entry]),
+                &[],
+                &[],
+                &Gitignore::empty(),
+            )
+            .await;
+            assert!(result.is_err(), "{entry}");
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::instance::INSTANCES_DIR;
+use crate::DEFAULT_TARGET_DIR;
+
+/// The file in the app root listing, with gitignore syntax, what isn't copied to the target.
+pub(crate) const IGNORE_FILENAME: &str = ".collieignore";
+
+/// Ignored even without an ignore file, a `!` pattern in the file brings them back.
+const DEFAULT_IGNORES: [&str; 4] = [".git/", ".DS_Store", DEFAULT_TARGET_DIR, INSTANCES_DIR];
+
+/// Load the ignore rules of the app in `root`: the defaults followed by the lines of its
+/// `.collieignore` if there is one.
+pub(crate) fn load(root: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for line in DEFAULT_IGNORES {
+        builder
+            .add_line(None, line)
+            .with_context(|| format!("illegal default ignore pattern: {line}"))?;
+    }
+    let ignore_file = root.join(IGNORE_FILENAME);
+    if ignore_file.is_file() {
+        if let Some(err) = builder.add(&ignore_file) {
+            return Err(err).with_context(|| format!("parse {}", ignore_file.display()));
+        }
+    }
+    builder.build().context("build ignore rules")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        std::fs::write(
+            root.join(IGNORE_FILENAME),
+            "data/\n*.log\n!keep.log\n!.render\n",
+        )
+        .unwrap();
+
+        let ignore = load(root).unwrap();
+        assert!(ignore.matched(root.join(".git"), true).is_ignore());
+        assert!(ignore.matched(root.join("data"), true).is_ignore());
+        assert!(!ignore.matched(root.join("data"), false).is_ignore());
+        assert!(ignore
+            .matched(root.join("config/redis.log"), false)
+            .is_ignore());
+        assert!(!ignore.matched(root.join("keep.log"), false).is_ignore());
+        assert!(!ignore.matched(root.join(".render"), true).is_ignore());
+        assert!(!ignore
+            .matched(root.join("config/redis.conf"), false)
+            .is_ignore());
+    }
+}
@@ -0,0 +1,419 @@
+use std::env;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bcrypt::{Version, DEFAULT_COST};
+use handlebars::{
+    Context,
+    Handlebars,
+    Helper,
+    HelperResult,
+    JsonRender,
+    JsonValue,
+    Output,
+    RenderContext,
+    RenderError,
+};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use super::rand_pass::{RandPassHelper, Secrets, MAX_PASS_LEN};
+
+/// Register every custom helper which is available to app templates and return the
+/// store of the secrets which `rand_pass` generates while rendering.
+pub(crate) fn register_helpers(handlebars: &mut Handlebars<'_>) -> Secrets {
+    let secrets = Secrets::default();
+    // {{rand_pass <local_var_name> <pass_len> [name="..."] [symbols=false] [charset="..."] ...}}
+    handlebars.register_helper("rand_pass", Box::new(RandPassHelper::new(secrets.clone())));
+    // {{uuid}}
+    handlebars.register_helper("uuid", Box::new(uuid_helper));
+    // {{rand_hex <len>}}
+    handlebars.register_helper("rand_hex", Box::new(rand_hex_helper));
+    // {{base64 <value>}} {{base64_decode <value>}}
+    handlebars.register_helper("base64", Box::new(base64_helper));
+    handlebars.register_helper("base64_decode", Box::new(base64_decode_helper));
+    // {{sha256 <value>}}
+    handlebars.register_helper("sha256", Box::new(sha256_helper));
+    // {{bcrypt <password> [cost=12]}} {{htpasswd <user> <password> [cost=12]}}
+    handlebars.register_helper("bcrypt", Box::new(bcrypt_helper));
+    handlebars.register_helper("htpasswd", Box::new(htpasswd_helper));
+    // {{default <value> <fallback>}}
+    handlebars.register_helper("default", Box::new(default_helper));
+    // {{upper <value>}} {{lower <value>}}
+    handlebars.register_helper("upper", Box::new(upper_helper));
+    handlebars.register_helper("lower", Box::new(lower_helper));
+    // {{to_yaml <value>}} {{to_json <value>}}
+    handlebars.register_helper("to_yaml", Box::new(to_yaml_helper));
+    handlebars.register_helper("to_json", Box::new(to_json_helper));
+    // {{indent <width> <value>}}
+    handlebars.register_helper("indent", Box::new(indent_helper));
+    // {{env <name> [<fallback>]}}
+    handlebars.register_helper("env", Box::new(env_helper));
+    secrets
+}
+
+/// Get the value of param `idx`, which must be present even outside strict mode.
+fn param<'a>(h: &'a Helper, idx: usize) -> Result<&'a JsonValue, RenderError> {
+    let param = h.param(idx).ok_or_else(|| {
+        RenderError::new(format!("Param {idx} is required for {} helper.", h.name()))
+    })?;
+    if param.is_value_missing() {
+        return Err(RenderError::strict_error(param.relative_path()));
+    }
+    Ok(param.value())
+}
+
+/// Get param `idx` rendered as a plain string.
+fn param_string(h: &Helper, idx: usize) -> Result<String, RenderError> {
+    Ok(param(h, idx)?.render())
+}
+
+fn param_u64(h: &Helper, idx: usize) -> Result<u64, RenderError> {
+    param(h, idx)?
+        .as_u64()
+        .ok_or_else(|| RenderError::new(format!("Param {idx} must be integer.")))
+}
+
+fn hash_cost(h: &Helper) -> Result<u32, RenderError> {
+    match h.hash_get("cost") {
+        Some(cost) => cost
+            .value()
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| RenderError::new("Hash cost must be integer.")),
+        None => Ok(DEFAULT_COST),
+    }
+}
+
+fn uuid_helper(
+    _: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&uuid::Uuid::new_v4().to_string())?;
+    Ok(())
+}
+
+fn rand_hex_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let len = param_u64(h, 0)?;
+    if len == 0 || len > MAX_PASS_LEN {
+        return Err(RenderError::new(format!(
+            "Param 0 must be between 1 and {MAX_PASS_LEN}."
+        )));
+    }
+    let mut rng = rand::thread_rng();
+    let hex: String = (0..len)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect();
+    out.write(&hex)?;
+    Ok(())
+}
+
+fn base64_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&BASE64.encode(param_string(h, 0)?))?;
+    Ok(())
+}
+
+fn base64_decode_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let decoded = BASE64
+        .decode(param_string(h, 0)?)
+        .map_err(|err| RenderError::new(format!("Can't decode base64: {err}.")))?;
+    let decoded = String::from_utf8(decoded)
+        .map_err(|_| RenderError::new("Decoded base64 is not utf-8 text."))?;
+    out.write(&decoded)?;
+    Ok(())
+}
+
+fn sha256_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let digest = Sha256::digest(param_string(h, 0)?.as_bytes());
+    out.write(&format!("{digest:x}"))?;
+    Ok(())
+}
+
+fn bcrypt_hash(password: &str, cost: u32) -> Result<String, RenderError> {
+    bcrypt::hash_with_result(password, cost)
+        .map(|parts| parts.format_for_version(Version::TwoY))
+        .map_err(|err| RenderError::new(format!("Can't hash password: {err}.")))
+}
+
+fn bcrypt_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&bcrypt_hash(&param_string(h, 0)?, hash_cost(h)?)?)?;
+    Ok(())
+}
+
+fn htpasswd_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let user = param_string(h, 0)?;
+    let hash = bcrypt_hash(&param_string(h, 1)?, hash_cost(h)?)?;
+    out.write(&format!("{user}:{hash}"))?;
+    Ok(())
+}
+
+fn default_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h
+        .param(0)
+        .filter(|v| !v.is_value_missing())
+        .map(|v| v.value())
+        .filter(|v| !matches!(v, JsonValue::Null) && v.as_str() != Some(""));
+    match value {
+        Some(value) => out.write(&value.render())?,
+        None => out.write(&param_string(h, 1)?)?,
+    }
+    Ok(())
+}
+
+fn upper_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&param_string(h, 0)?.to_uppercase())?;
+    Ok(())
+}
+
+fn lower_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&param_string(h, 0)?.to_lowercase())?;
+    Ok(())
+}
+
+fn to_yaml_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let yaml = serde_yaml::to_string(param(h, 0)?)
+        .map_err(|err| RenderError::new(format!("Can't serialize to yaml: {err}.")))?;
+    out.write(yaml.trim_end())?;
+    Ok(())
+}
+
+fn to_json_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    out.write(&param(h, 0)?.to_string())?;
+    Ok(())
+}
+
+fn indent_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let width = param_u64(h, 0)? as usize;
+    let prefix = " ".repeat(width);
+    let indented = param_string(h, 1)?
+        .lines()
+        .map(|line| {
+            if line.is_empty() {
+                line.to_owned()
+            } else {
+                format!("{prefix}{line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    out.write(&indented)?;
+    Ok(())
+}
+
+fn env_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let name = param_string(h, 0)?;
+    let value = match env::var(&name) {
+        Ok(v) => v,
+        Err(_) if h.param(1).is_some() => param_string(h, 1)?,
+        Err(err) => return Err(RenderError::new(format!("Can't read env {name}: {err}."))),
+    };
+    out.write(&value)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use handlebars::no_escape;
+
+    use super::*;
+
+    fn render(template: &str) -> Result<String, RenderError> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(no_escape);
+        handlebars.set_strict_mode(true);
+        register_helpers(&mut handlebars);
+        let data: JsonValue = serde_yaml::from_str(
+            "variables:\n  password:\n    value: secret\n  empty:\n    value: ''\nports:\n  redis:\n    port: 6379\n",
+        )
+        .unwrap();
+        handlebars.render_template(template, &data)
+    }
+
+    #[test]
+    fn test_uuid() {
+        let uuid = render("{{uuid}}").unwrap();
+        assert_eq!(36, uuid.len());
+        assert_ne!(uuid, render("{{uuid}}").unwrap());
+    }
+
+    #[test]
+    fn test_rand_hex() {
+        let hex = render("{{rand_hex 24}}").unwrap();
+        assert_eq!(24, hex.len());
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
+        assert!(render("{{rand_hex 0}}").is_err());
+        assert!(render("{{rand_hex 1025}}").is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(
+            "c2VjcmV0",
+            render("{{base64 variables.password.value}}").unwrap()
+        );
+        assert_eq!("secret", render(r#"{{base64_decode "c2VjcmV0"}}"#).unwrap());
+        assert!(render(r#"{{base64_decode "%%%"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b",
+            render("{{sha256 variables.password.value}}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bcrypt() {
+        let hash = render("{{bcrypt variables.password.value cost=4}}").unwrap();
+        assert!(hash.starts_with("$2y$04$"));
+        assert!(bcrypt::verify("secret", &hash).unwrap());
+    }
+
+    #[test]
+    fn test_htpasswd() {
+        let line = render(r#"{{htpasswd "admin" variables.password.value cost=4}}"#).unwrap();
+        let (user, hash) = line.split_once(':').unwrap();
+        assert_eq!("admin", user);
+        assert!(bcrypt::verify("secret", hash).unwrap());
+    }
+
+    #[test]
+    fn test_default() {
+        assert_eq!(
+            "secret",
+            render(r#"{{default variables.password.value "x"}}"#).unwrap()
+        );
+        assert_eq!(
+            "x",
+            render(r#"{{default variables.empty.value "x"}}"#).unwrap()
+        );
+        assert_eq!(
+            "x",
+            render(r#"{{default variables.missing.value "x"}}"#).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_upper_lower() {
+        assert_eq!(
+            "SECRET",
+            render("{{upper variables.password.value}}").unwrap()
+        );
+        assert_eq!("secret", render(r#"{{lower "SeCrEt"}}"#).unwrap());
+        assert!(render("{{upper variables.missing.value}}").is_err());
+    }
+
+    #[test]
+    fn test_to_yaml_json() {
+        assert_eq!("port: 6379", render("{{to_yaml ports.redis}}").unwrap());
+        assert_eq!(
+            r#"{"port":6379}"#,
+            render("{{to_json ports.redis}}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_indent() {
+        assert_eq!(
+            "a:\n    port: 6379",
+            render("a:\n{{indent 4 (to_yaml ports.redis)}}").unwrap()
+        );
+        assert_eq!("  a\n\n  b", render(r#"{{indent 2 "a\n\nb"}}"#).unwrap());
+    }
+
+    #[test]
+    fn test_env() {
+        env::set_var("COLLIE_HELPER_TEST", "from-env");
+        assert_eq!(
+            "from-env",
+            render(r#"{{env "COLLIE_HELPER_TEST"}}"#).unwrap()
+        );
+        assert_eq!(
+            "x",
+            render(r#"{{env "COLLIE_HELPER_MISSING" "x"}}"#).unwrap()
+        );
+        assert!(render(r#"{{env "COLLIE_HELPER_MISSING"}}"#).is_err());
+    }
+}
@@ -1,115 +1,66 @@
+pub(crate) mod collieignore;
+pub(crate) mod helpers;
 mod rand_pass;
+mod render_error;
+pub(crate) mod sync;
 
 use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::path::Path;
-use std::process::Stdio;
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Context, Result};
-use async_recursion::async_recursion;
+use anyhow::{anyhow, bail, Context, Result};
 use futures::{pin_mut, select, FutureExt};
-use handlebars::{no_escape, Handlebars};
-use serde::{Deserialize, Serialize};
+use handlebars::{no_escape, to_json, Handlebars};
+use log::{error, info, warn};
 use tokio::fs;
-use tokio::process::Command;
 use tokio_util::sync::CancellationToken;
-use which::which;
-
-use self::rand_pass::rand_pass_helper;
-use crate::compose_helper::compose;
-use crate::MANIFEST_FILENAME;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Metadata {
-    name: String,
-    desc: String,
-    tags: Vec<String>,
-    version: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Port {
-    ip: String,
-    port: u16,
-    desc: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Variable {
-    name: String,
-    desc: String,
-    value: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Manifest {
-    metadata: Metadata,
-    templates: Vec<String>,
-    ports: HashMap<String, Port>,
-    variables: HashMap<String, Variable>,
-}
 
-#[async_recursion]
-async fn copy_dirs<S: AsRef<Path> + Send, D: AsRef<Path> + Send>(from: S, to: D) -> Result<()> {
-    let from = from.as_ref().canonicalize().context("get from abs path")?;
-    let to = to.as_ref().canonicalize().context("get to abs path")?;
+use self::helpers::register_helpers;
+use crate::compose_helper::{compose, resolve_project_name};
+use crate::hook::{contract_envs, env_key, manifest_envs, run_hook, write_env_file, Action, Phase};
+use crate::instance::{self, INSTANCES_DIR};
+use crate::manifest::Manifest;
+use crate::rollback::rollback;
+use crate::state::{rendered_files, State};
+use crate::{backup, registry, snapshot, target_dir, template_files, validate, DEFAULT_TARGET_DIR};
 
-    // Check if the source and destination are valid directories
-    if !from.is_dir() || !to.is_dir() {
-        bail!("source and destination must be directories");
-    }
-
-    // Iterate over the entries in the source directory
-    let mut read_dir = fs::read_dir(from).await.context("read dir")?;
-    while let Some(entry) = read_dir.next_entry().await.context("get dir next entry")? {
-        let path = entry.path();
-
-        // Get the file name of the entry
-        let file_name = match path.file_name() {
-            Some(name) => name,
-            None => continue, // Skip if no file name
-        };
-
-        if path == to {
-            continue; // Skip if file self ref
-        }
-
-        // Construct the new path by joining the destination and the file name
-        let new_path = to.join(file_name);
-        // Copy the entry to the new path
-        if path.is_file() {
-            if new_path.exists() {
-                // Remove if is exists
-                fs::remove_file(&new_path).await.context("remove file")?;
-            }
-            fs::copy(&path, &new_path).await.context("copy file")?;
-        } else if path.is_dir() {
-            if new_path.exists() {
-                // Remove if is exists
-                fs::remove_dir_all(&new_path).await.context("remove dir")?;
-            }
-            // If the entry is a directory, create a new directory and recursively copy its contents
-            fs::create_dir_all(&new_path)
-                .await
-                .context("create target dir")?;
-            copy_dirs(&path, &new_path).await?;
-        }
-    }
-    Ok(())
+/// How [`render_and_up`] deploys the app.
+pub(super) struct Options {
+    /// Either [`Action::Up`] or [`Action::Upgrade`].
+    pub(super) action: Action,
+    /// Only render, neither run the hooks of `action` nor compose.
+    pub(super) dry: bool,
+    /// Allow a non-empty target dir which [`target_dir::guard`] refuses.
+    pub(super) force: bool,
+    /// Overwrite the files hand-edited in the target since the last render.
+    pub(super) overwrite_drift: bool,
+    /// Restore the previous deployment when this one fails.
+    pub(super) rollback_on_failure: bool,
+    /// Back up the data of the previous deployment first.
+    pub(super) backup: bool,
+    /// The compose project name instead of the one [`resolve_project_name`] finds.
+    pub(super) project_name: Option<String>,
+    /// The named instance deployed in the target, which gets its own project and ports.
+    pub(super) instance: Option<String>,
 }
 
+/// Render the app in `dir` into `target` and bring it up, running the hooks of the
+/// render phases and of the action. The files of the deployment it replaces are kept in
+/// a snapshot for [`rollback`].
 pub(super) async fn render_and_up<P: AsRef<Path>, T: AsRef<Path>>(
     dir: P,
     target: T,
-    dry: bool,
+    options: Options,
     token: CancellationToken,
 ) -> Result<()> {
     let dir = dir.as_ref();
     let target = target.as_ref();
+    let action = options.action;
 
     let wait_for_cancel = token.cancelled().fuse();
     pin_mut!(wait_for_cancel);
 
+    target_dir::guard(dir, target, options.force).await?;
+
     // check if target exist and create it
     if !target.exists() {
         select! {
@@ -120,70 +71,245 @@ pub(super) async fn render_and_up<P: AsRef<Path>, T: AsRef<Path>>(
         }
     }
 
-    copy_dirs(dir, target)
-        .await
-        .context("copy file to target dir")?;
+    // the state or else the manifest copied by the last up tells which version is deployed
+    let state = State::load(target).await.ok();
+    let previous_version = match &state {
+        Some(state) => Some(state.version.clone()),
+        None => Manifest::from_dir(target)
+            .await
+            .ok()
+            .map(|v| v.metadata.version),
+    };
+    if matches!(action, Action::Upgrade) && previous_version.is_none() {
+        bail!("no app deployed in {}, use up instead", target.display());
+    }
 
-    let manifest_file_path = dir.join(MANIFEST_FILENAME);
-    let manifest_content = select! {
+    let mut manifest = select! {
         _ = wait_for_cancel => return Ok(()),
-        result = fs::read_to_string(&manifest_file_path).fuse() => {
-            result.with_context(|| format!("read file: {}", manifest_file_path.display()))?
-        }
+        result = Manifest::from_dir(dir).fuse() => result?
     };
-    let manifest: Manifest =
-        serde_yaml::from_str(&manifest_content).context("can't parse config")?;
+    validate::check_deployable(dir, &manifest)?;
+    if options.instance.is_some() {
+        instance::assign_ports(dir, target, &mut manifest).await?;
+    }
+
+    // hand-edited files in the target would silently be overwritten by the sync
+    if let Some(state) = &state {
+        let drifts = state.drift(target).await.context("detect drift")?;
+        if !drifts.is_empty() && !options.overwrite_drift {
+            bail!(
+                "{} file(s) in {} changed since the last render ({}), pass --overwrite-drift to overwrite them",
+                drifts.len(),
+                target.display(),
+                drifts
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        for drift in &drifts {
+            warn!("overwrite drifted file, {drift}");
+        }
+    }
+
+    let snapshotted = !options.dry && snapshot::take(target).await.context("take snapshot")?;
+
+    let project = resolve_project_name(
+        options.project_name.as_deref(),
+        options.instance.as_deref(),
+        target,
+        Some(&manifest),
+    )
+    .await?;
+    // only now, an unmarked target without state is a deployment which predates both
+    target_dir::mark(target).await?;
+    if options.backup && !options.dry && previous_version.is_some() {
+        let archive = backup::create(target, &project, None, token.clone())
+            .await
+            .context("back up before upgrade")?;
+        info!("backed up to {}", archive.display());
+    }
+    let result = deploy(
+        dir,
+        target,
+        &manifest,
+        &options,
+        previous_version.as_deref(),
+        &project,
+        token.clone(),
+    )
+    .await;
+    if result.is_ok() && !options.dry {
+        snapshot::settle(target).await?;
+    }
+    match (result, previous_version) {
+        (Err(err), Some(previous_version)) if options.rollback_on_failure && snapshotted => {
+            error!("{action} failed, roll back to {previous_version}: {err:#}");
+            match rollback(target, Some(project.as_str()), token.clone()).await {
+                Ok(version) => Err(err.context(format!("rolled back to {version}"))),
+                Err(rollback_err) => {
+                    Err(err.context(format!("roll back failed too: {rollback_err:#}")))
+                },
+            }
+        },
+        (result, _) => result,
+    }
+}
+
+/// Sync, render and bring up the app, the part of [`render_and_up`] which may need a
+/// rollback.
+async fn deploy(
+    dir: &Path,
+    target: &Path,
+    manifest: &Manifest,
+    options: &Options,
+    previous_version: Option<&str>,
+    project: &str,
+    token: CancellationToken,
+) -> Result<()> {
+    let action = options.action;
+
+    // expand globs and directories against the app root, never descending into a target
+    let mut skip = vec![
+        PathBuf::from(DEFAULT_TARGET_DIR),
+        PathBuf::from(INSTANCES_DIR),
+    ];
+    if let (Ok(dir), Ok(target)) = (dir.canonicalize(), target.canonicalize()) {
+        skip.extend(target.strip_prefix(&dir).map(Path::to_path_buf));
+    }
+    let ignore = dir
+        .canonicalize()
+        .context("get app abs path")
+        .and_then(|dir| collieignore::load(&dir))?;
+    let templates = template_files::expand(
+        dir,
+        &manifest.templates,
+        &manifest.templates_exclude,
+        &skip,
+        &ignore,
+    )
+    .await
+    .context("expand templates")?;
+    let report = sync::sync(dir, target, &ignore, &templates)
+        .await
+        .context("sync app to target dir")?;
+    for name in &report.added {
+        info!("add {name}");
+    }
+    for name in &report.updated {
+        info!("update {name}");
+    }
+    for name in &report.removed {
+        info!("remove {name}");
+    }
+    info!("sync app to {}: {report}", target.display());
+
+    let mut resolved_envs = manifest_envs(manifest);
+    let mut envs = contract_envs(action, target, Some(manifest), previous_version, project);
+    envs.extend(resolved_envs.iter().cloned());
+    run_hook(
+        target,
+        &manifest.hooks,
+        Phase::PreRender,
+        &envs,
+        token.clone(),
+    )
+    .await?;
 
     // create the handlebars registry
     let mut handlebars = Handlebars::new();
     handlebars.register_escape_fn(no_escape);
     handlebars.set_strict_mode(true);
-    // {{rand_pass <local_var_name> <pass_len>}}
-    handlebars.register_helper("rand_pass", Box::new(rand_pass_helper));
-    for template_rel_path in &manifest.templates {
+    let secrets = register_helpers(&mut handlebars);
+    let mut template_sources = HashMap::new();
+    for template_rel_path in &templates {
         let template_file_path = target.join(template_rel_path);
         let template_content = fs::read_to_string(template_file_path)
             .await
-            .context("read template content")?;
+            .with_context(|| format!("read template content: {template_rel_path}"))?;
         handlebars
             .register_template_string(template_rel_path, &template_content)
-            .context("compile template file")?;
+            .with_context(|| format!("compile template file: {template_rel_path}"))?;
+        template_sources.insert(template_rel_path, template_content);
     }
 
-    for template_rel_path in &manifest.templates {
+    let data = to_json(manifest);
+    for template_rel_path in &templates {
         let template_file_path = target.join(template_rel_path);
         let final_file_content = handlebars
-            .render(template_rel_path, &manifest)
+            .render(template_rel_path, manifest)
+            .map_err(|err| {
+                let source = &template_sources[template_rel_path];
+                anyhow!(
+                    "{}",
+                    render_error::describe(&err, template_rel_path, source, &data)
+                )
+            })
             .context("render template")?;
         fs::write(template_file_path, final_file_content)
             .await
             .context("write result to file")?;
     }
-    if dry {
+
+    // hand the resolved variables and the generated secrets to the hooks
+    let secret_envs: Vec<_> = secrets
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, secret)| (env_key("COLLIE_SECRET_", name), secret.clone()))
+        .collect();
+    resolved_envs.extend(secret_envs.iter().cloned());
+    // a dry run leaves no secrets on disk
+    if !options.dry {
+        write_env_file(target, &resolved_envs)
+            .await
+            .context("write env file")?;
+    }
+    envs.extend(secret_envs);
+    run_hook(
+        target,
+        &manifest.hooks,
+        Phase::PostRender,
+        &envs,
+        token.clone(),
+    )
+    .await?;
+    // the files are rendered now whatever comes next, keep the record in step so they
+    // don't look hand-edited after a dry run or a failed deploy
+    if let Ok(mut state) = State::load(target).await {
+        state.files = rendered_files(target).await?;
+        state.save(target).await.context("save deployment state")?;
+    }
+    if options.dry {
         return Ok(());
     }
 
-    // run custom init script
-    let init_script_file = target
-        .join("scripts")
-        .join("init.sh")
-        .canonicalize()
-        .context("init.sh path illegal")?;
-    let shell = which("sh").context("can't find your sh")?;
-    Command::new(shell)
-        .args([OsStr::new("-c"), init_script_file.as_os_str()])
-        .current_dir(target)
-        .kill_on_drop(true)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()
-        .await
-        .with_context(|| format!("run 'sh -c {}'", init_script_file.display()))?;
+    run_hook(
+        target,
+        &manifest.hooks,
+        action.pre_phase(),
+        &envs,
+        token.clone(),
+    )
+    .await?;
 
     // compose up the app
-    compose(token.clone(), &target, ["up", "-d"])
+    compose(token.clone(), &target, project, ["up", "-d"])
         .await
         .context("run 'docker[.exe] compose up -d'")?;
+
+    run_hook(
+        target,
+        &manifest.hooks,
+        action.post_phase(),
+        &envs,
+        token.clone(),
+    )
+    .await?;
+
+    let mut state = State::new(action, target, manifest, project).await?;
+    state.save(target).await.context("save deployment state")?;
+    registry::record(Some(dir), target, &state).await;
     Ok(())
 }
@@ -1,48 +1,156 @@
-use handlebars::{Context, Handlebars, Helper, Output, RenderContext, RenderError};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use handlebars::{
+    Context,
+    Handlebars,
+    Helper,
+    HelperDef,
+    HelperResult,
+    Output,
+    RenderContext,
+    RenderError,
+};
 use passwords::PasswordGenerator;
+use rand::seq::SliceRandom;
+
+use crate::logger::mask_secret;
+
+/// The passwords generated during one render keyed by their `name`.
+pub(crate) type Secrets = Arc<Mutex<BTreeMap<String, String>>>;
+
+/// The longest password rand_pass is allowed to generate.
+pub(super) const MAX_PASS_LEN: u64 = 1024;
+
+fn hash_bool(h: &Helper, name: &str, default: bool) -> Result<bool, RenderError> {
+    match h.hash_get(name) {
+        Some(v) => v
+            .value()
+            .as_bool()
+            .ok_or_else(|| RenderError::new(format!("Hash {name} must be boolean."))),
+        None => Ok(default),
+    }
+}
 
-pub(crate) fn rand_pass_helper(
-    h: &Helper,
-    _: &Handlebars,
-    _: &Context,
-    rc: &mut RenderContext,
-    _: &mut dyn Output,
-) -> Result<(), RenderError> {
+/// {{rand_pass <local_var_name> <pass_len> [name="..."] [numbers=true] [lowercase=true]
+/// [uppercase=true] [symbols=true] [exclude_similar=false] [charset="..."]}}
+///
+/// The generated password is stored in the local variable `@<local_var_name>`. When a
+/// `charset` is given the password is drawn from exactly those characters and the
+/// character class options are ignored.
+///
+/// Every call generates a new password, unless it's given a `name`. Every template
+/// rendered by the same registry shares the named passwords, so the same `name` resolves
+/// to the same password everywhere and can be handed to the hooks afterwards.
+#[derive(Default)]
+pub(crate) struct RandPassHelper {
+    secrets: Secrets,
+}
+
+impl RandPassHelper {
+    pub(crate) fn new(secrets: Secrets) -> Self {
+        Self {
+            secrets,
+        }
+    }
+}
+
+impl HelperDef for RandPassHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        _: &mut dyn Output,
+    ) -> HelperResult {
+        let (var_name, password) = rand_pass(h, &self.secrets)?;
+        let block = rc.block_mut().unwrap();
+        block.set_local_var(&var_name, password.into());
+        Ok(())
+    }
+}
+
+fn rand_pass(h: &Helper, secrets: &Secrets) -> Result<(String, String), RenderError> {
     // get parameter from helper or throw an error
-    let key = h.param(0).ok_or(RenderError::new(
+    let var_name = h.param(0).ok_or(RenderError::new(
         "Param 0 is required for rand_pass helper.",
     ))?;
-    let key = key
+    let var_name = var_name
         .value()
         .as_str()
         .ok_or(RenderError::new("Param 0 must be string."))?;
-    if key.is_empty() {
+    if var_name.is_empty() {
         return Err(RenderError::new("Param 0 can't be empty."));
     }
     // get parameter from helper or throw an error
     let pass_len = h.param(1).ok_or(RenderError::new(
-        "Param 0 is required for rand_pass helper.",
+        "Param 1 is required for rand_pass helper.",
     ))?;
     let pass_len = pass_len
         .value()
         .as_u64()
         .ok_or(RenderError::new("Param 1 must be integer."))?;
-    let pg = PasswordGenerator {
-        length: pass_len as usize,
-        numbers: true,
-        lowercase_letters: true,
-        uppercase_letters: true,
-        symbols: true,
-        spaces: false,
-        exclude_similar_characters: false,
-        strict: true,
+    if pass_len == 0 || pass_len > MAX_PASS_LEN {
+        return Err(RenderError::new(format!(
+            "Param 1 must be between 1 and {MAX_PASS_LEN}."
+        )));
+    }
+
+    let name = match h.hash_get("name") {
+        Some(name) => {
+            let name = name
+                .value()
+                .as_str()
+                .ok_or(RenderError::new("Hash name must be string."))?;
+            if name.is_empty() {
+                return Err(RenderError::new("Hash name can't be empty."));
+            }
+            Some(name)
+        },
+        None => None,
+    };
+    let mut secrets = secrets.lock().unwrap();
+    if let Some(password) = name.and_then(|v| secrets.get(v)) {
+        return Ok((var_name.to_owned(), password.clone()));
+    }
+
+    let password: String = match h.hash_get("charset") {
+        Some(charset) => {
+            let charset: Vec<char> = charset
+                .value()
+                .as_str()
+                .ok_or(RenderError::new("Hash charset must be string."))?
+                .chars()
+                .collect();
+            if charset.is_empty() {
+                return Err(RenderError::new("Hash charset can't be empty."));
+            }
+            let mut rng = rand::thread_rng();
+            (0..pass_len)
+                .map(|_| *charset.choose(&mut rng).unwrap())
+                .collect()
+        },
+        None => {
+            let pg = PasswordGenerator {
+                length: pass_len as usize,
+                numbers: hash_bool(h, "numbers", true)?,
+                lowercase_letters: hash_bool(h, "lowercase", true)?,
+                uppercase_letters: hash_bool(h, "uppercase", true)?,
+                symbols: hash_bool(h, "symbols", true)?,
+                spaces: false,
+                exclude_similar_characters: hash_bool(h, "exclude_similar", false)?,
+                strict: true,
+            };
+            pg.generate_one()
+                .map_err(|err| RenderError::new(format!("Can't generate password: {err:#}.")))?
+        },
     };
-    let password = pg
-        .generate_one()
-        .map_err(|err| RenderError::new(format!("Can't generate password: {err:#}.")))?;
-    let block = rc.block_mut().unwrap();
-    block.set_local_var(key, password.into());
-    Ok(())
+    mask_secret(password.as_str());
+    if let Some(name) = name {
+        secrets.insert(name.to_owned(), password.clone());
+    }
+    Ok((var_name.to_owned(), password))
 }
 
 #[cfg(test)]
@@ -53,12 +161,21 @@ mod test {
 
     use super::*;
 
+    fn render(template: &str) -> Result<String, RenderError> {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(no_escape);
+        handlebars.set_strict_mode(true);
+        handlebars.register_helper("rand_pass", Box::new(RandPassHelper::default()));
+        let data: HashMap<String, String> = HashMap::new();
+        handlebars.render_template(template, &data)
+    }
+
     #[test]
     fn test_rand_pass_len() {
         let mut handlebars = Handlebars::new();
         handlebars.register_escape_fn(no_escape);
         handlebars.set_strict_mode(true);
-        handlebars.register_helper("rand_pass", Box::new(rand_pass_helper));
+        handlebars.register_helper("rand_pass", Box::new(RandPassHelper::default()));
         handlebars
             .register_template_string("test", r#"{{rand_pass "my_pass" 20}}{{@my_pass}}"#)
             .unwrap();
@@ -66,4 +183,57 @@ mod test {
         let data: HashMap<String, String> = HashMap::new();
         assert_eq!(20, handlebars.render("test", &data).unwrap().len());
     }
+
+    #[test]
+    fn test_rand_pass_options() {
+        let password =
+            render(r#"{{rand_pass "my_pass" 64 symbols=false exclude_similar=true}}{{@my_pass}}"#)
+                .unwrap();
+        assert_eq!(64, password.len());
+        assert!(password.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert!(!password.contains(['0', 'O', 'o', '1', 'l', 'I', 'i']));
+
+        let password = render(r#"{{rand_pass "my_pass" 32 charset="ab"}}{{@my_pass}}"#).unwrap();
+        assert_eq!(32, password.len());
+        assert!(password.chars().all(|c| c == 'a' || c == 'b'));
+    }
+
+    #[test]
+    fn test_rand_pass_invalid() {
+        assert!(render(r#"{{rand_pass "my_pass" 0}}"#).is_err());
+        assert!(render(r#"{{rand_pass "my_pass" 4096}}"#).is_err());
+        assert!(render(r#"{{rand_pass "my_pass" 8 charset=""}}"#).is_err());
+        let err = render(r#"{{rand_pass "my_pass"}}"#).unwrap_err();
+        assert!(err.desc.contains("Param 1 is required"));
+    }
+
+    #[test]
+    fn test_rand_pass_shared() {
+        let secrets = Secrets::default();
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(no_escape);
+        handlebars.register_helper("rand_pass", Box::new(RandPassHelper::new(secrets.clone())));
+
+        let data: HashMap<String, String> = HashMap::new();
+        let template = r#"{{rand_pass "my_pass" 20 name="db"}}{{@my_pass}}"#;
+        let first = handlebars.render_template(template, &data).unwrap();
+        let second = handlebars.render_template(template, &data).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(Some(&first), secrets.lock().unwrap().get("db"));
+    }
+
+    #[test]
+    fn test_rand_pass_unnamed() {
+        let secrets = Secrets::default();
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(no_escape);
+        handlebars.register_helper("rand_pass", Box::new(RandPassHelper::new(secrets.clone())));
+
+        let data: HashMap<String, String> = HashMap::new();
+        let template = r#"{{rand_pass "my_pass" 20}}{{@my_pass}}"#;
+        let first = handlebars.render_template(template, &data).unwrap();
+        let second = handlebars.render_template(template, &data).unwrap();
+        assert_ne!(first, second);
+        assert!(secrets.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,156 @@
+use std::fmt::Write;
+
+use handlebars::{JsonValue, RenderError};
+use regex::Regex;
+
+lazy_static::lazy_static! {
+    static ref STRICT_REGEX: Regex = Regex::new(r#"^Variable "(.+)" not found in strict mode\.$"#).unwrap();
+}
+
+/// How many lines are shown before and after the failing line.
+const SNIPPET_RANGE: usize = 2;
+
+/// Describe a render failure with its location in the template, a snippet of the
+/// surrounding lines and, for strict mode lookups, the keys which are available.
+pub(crate) fn describe(
+    err: &RenderError,
+    template_name: &str,
+    source: &str,
+    data: &JsonValue,
+) -> String {
+    let mut buf = String::new();
+    match (err.line_no, err.column_no) {
+        (Some(line), Some(col)) => {
+            let _ = writeln!(buf, "{template_name}:{line}:{col}: {}", err.desc);
+            buf.push_str(&snippet(source, line, col));
+        },
+        _ => {
+            let _ = writeln!(buf, "{template_name}: {}", err.desc);
+        },
+    }
+    if let Some(captures) = STRICT_REGEX.captures(&err.desc) {
+        buf.push_str(&lookup_hint(&captures[1], data));
+    }
+    buf.trim_end().to_owned()
+}
+
+fn snippet(source: &str, line: usize, col: usize) -> String {
+    let mut buf = String::new();
+    let first = line.saturating_sub(SNIPPET_RANGE).max(1);
+    let lines = source
+        .lines()
+        .enumerate()
+        .map(|(idx, content)| (idx + 1, content))
+        .skip(first - 1)
+        .take(line + SNIPPET_RANGE + 1 - first);
+    for (line_no, content) in lines {
+        let _ = writeln!(buf, "{line_no:>4} | {content}");
+        if line_no == line {
+            // underline the whole expression starting at the reported column
+            let start = col.saturating_sub(1);
+            let rest: String = content.chars().skip(start).collect();
+            let width = rest
+                .find("}}")
+                .map(|end| rest[..end].chars().count() + 2)
+                .unwrap_or(1);
+            let _ = writeln!(buf, "     | {}{}", " ".repeat(start), "^".repeat(width));
+        }
+    }
+    buf
+}
+
+fn lookup_hint(expression: &str, data: &JsonValue) -> String {
+    let expression = expression
+        .trim_start_matches("@root.")
+        .trim_start_matches("this.")
+        .trim_start_matches("./");
+    let segments: Vec<&str> = expression
+        .split(['.', '/'])
+        .map(|segment| segment.trim_start_matches('[').trim_end_matches(']'))
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let mut current = data;
+    for (idx, segment) in segments.iter().enumerate() {
+        let parent = segments[..idx].join(".");
+        let JsonValue::Object(map) = current else {
+            return format!("{parent} is not an object, so it has no key {segment}\n");
+        };
+        if let Some(value) = map.get(*segment) {
+            current = value;
+            continue;
+        }
+
+        let missing = segments[..=idx].join(".");
+        let prefix = if parent.is_empty() {
+            String::new()
+        } else {
+            format!("{parent}.")
+        };
+        let mut keys: Vec<&str> = map.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+
+        let mut hint = format!("{missing} not found");
+        let threshold = (segment.chars().count() / 3).max(1);
+        let suggestion = keys
+            .iter()
+            .map(|candidate| (strsim::levenshtein(candidate, segment), candidate))
+            .filter(|(distance, _)| *distance <= threshold)
+            .min_by_key(|(distance, _)| *distance);
+        if let Some((_, candidate)) = suggestion {
+            let _ = write!(hint, "; did you mean {prefix}{candidate}?");
+        }
+        hint.push('\n');
+        if keys.is_empty() {
+            let _ = writeln!(hint, "{} has no keys", display_scope(&parent));
+        } else {
+            let _ = writeln!(
+                hint,
+                "available keys under {}: {}",
+                display_scope(&parent),
+                keys.join(", ")
+            );
+        }
+        return hint;
+    }
+    String::new()
+}
+
+fn display_scope(parent: &str) -> &str {
+    if parent.is_empty() {
+        "the manifest root"
+    } else {
+        parent
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use handlebars::{no_escape, Handlebars};
+
+    use super::*;
+
+    #[test]
+    fn test_describe_missing_variable() {
+        let source = "a: 1\nb: {{variables.pasword.value}}\nc: 3\n";
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(no_escape);
+        handlebars.set_strict_mode(true);
+        handlebars
+            .register_template_string("test.yaml", source)
+            .unwrap();
+
+        let data: JsonValue = serde_yaml::from_str(
+            "variables:\n  password:\n    value: secret\n  timeout:\n    value: 0\n",
+        )
+        .unwrap();
+        let err = handlebars.render("test.yaml", &data).unwrap_err();
+        let message = describe(&err, "test.yaml", source, &data);
+
+        assert!(message.starts_with("test.yaml:2:4: "));
+        assert!(message.contains("   2 | b: {{variables.pasword.value}}"));
+        assert!(message.contains("     |    ^^^^^^^^^^^^^^^^^^^^^^^^^^^"));
+        assert!(message.contains("variables.pasword not found; did you mean variables.password?"));
+        assert!(message.contains("available keys under variables: password, timeout"));
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use async_recursion::async_recursion;
+use filetime::FileTime;
+use ignore::gitignore::Gitignore;
+use log::{debug, warn};
+use serde_yaml::Value;
+use tokio::fs;
+
+use crate::state::hash_file;
+use crate::template_files::to_template_name;
+
+/// Lists the files the last sync copied, so that files removed from the app are removed
+/// from the target too while files created there at runtime are left alone.
+pub(crate) const SYNCED_FILENAME: &str = ".collie.files";
+
+/// Lists the synced files which the last up rendered as templates.
+pub(crate) const RENDERED_FILENAME: &str = ".collie.rendered";
+
+const COMPOSE_FILENAMES: [&str; 4] = [
+    "docker-compose.yaml",
+    "docker-compose.yml",
+    "compose.yaml",
+    "compose.yml",
+];
+
+/// What a sync changed in the target, by path relative to it. Templates are rewritten by
+/// the render anyway and don't show up here.
+#[derive(Debug, Default)]
+pub(super) struct SyncReport {
+    pub(super) added: Vec<String>,
+    pub(super) updated: Vec<String>,
+    pub(super) removed: Vec<String>,
+    pub(super) unchanged: usize,
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} removed, {} unchanged",
+            self.added.len(),
+            self.updated.len(),
+            self.removed.len(),
+            self.unchanged
+        )
+    }
+}
+
+/// The files and symlinks of the app by path relative to its root.
+#[derive(Default)]
+struct Listing {
+    files: Vec<PathBuf>,
+    links: Vec<PathBuf>,
+}
+
+#[async_recursion]
+async fn list_entries(
+    root: &Path,
+    skip: &Path,
+    ignore: &Gitignore,
+    rel_dir: PathBuf,
+    listing: &mut Listing,
+) -> Result<()> {
+    let dir = root.join(&rel_dir);
+    let mut read_dir = fs::read_dir(&dir)
+        .await
+        .with_context(|| format!("read dir: {}", dir.display()))?;
+    while let Some(entry) = read_dir.next_entry().await.context("get dir next entry")? {
+        let path = entry.path();
+        if path == skip {
+            continue; // Skip if file self ref
+        }
+        let file_type = entry.file_type().await.context("get file type")?;
+        if ignore.matched(&path, file_type.is_dir()).is_ignore() {
+            continue; // Skip if listed in the ignore file
+        }
+        let rel_path = rel_dir.join(entry.file_name());
+        if file_type.is_symlink() {
+            listing.links.push(rel_path);
+        } else if file_type.is_dir() {
+            list_entries(root, skip, ignore, rel_path, listing).await?;
+        } else if file_type.is_file() {
+            listing.files.push(rel_path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `dst` already holds the content of `src`: same size and mode and either the
+/// same mtime or, when only the mtime differs, the same hash.
+async fn is_unchanged(src: &Path, dst: &Path) -> Result<bool> {
+    let Ok(dst_metadata) = fs::symlink_metadata(dst).await else {
+        return Ok(false);
+    };
+    let src_metadata = fs::metadata(src)
+        .await
+        .with_context(|| format!("get metadata: {}", src.display()))?;
+    if !dst_metadata.is_file() || src_metadata.len() != dst_metadata.len() {
+        return Ok(false);
+    }
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if src_metadata.permissions().mode() != dst_metadata.permissions().mode() {
+            return Ok(false);
+        }
+    }
+    if FileTime::from_last_modification_time(&src_metadata)
+        == FileTime::from_last_modification_time(&dst_metadata)
+    {
+        return Ok(true);
+    }
+    Ok(hash_file(src).await? == hash_file(dst).await?)
+}
+
+/// Resolve where the symlink `rel_path` of the app points to, relative to the app `root`.
+/// `None` when it points outside the app.
+fn resolve_link(root: &Path, rel_path: &Path, link_target: &Path) -> Option<PathBuf> {
+    let (base, rest) = if link_target.is_absolute() {
+        (PathBuf::new(), link_target.strip_prefix(root).ok()?)
+    } else {
+        (
+            rel_path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            link_target,
+        )
+    };
+    let mut resolved = base;
+    for component in rest.components() {
+        match component {
+            Component::Normal(v) => resolved.push(v),
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            },
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+/// The path from the dir of the symlink `rel_path` to `resolved`, both relative to the
+/// app root, so that the copied link points into the target instead of the app.
+fn relative_link(rel_path: &Path, resolved: &Path) -> PathBuf {
+    let depth = rel_path.components().count().saturating_sub(1);
+    let mut link_target: PathBuf = std::iter::repeat("..").take(depth).collect();
+    link_target.push(resolved);
+    link_target
+}
+
+#[cfg(target_family = "unix")]
+pub(crate) async fn create_link(
+    link_target: &Path,
+    dst: &Path,
+    _resolved: &Path,
+) -> std::io::Result<()> {
+    fs::symlink(link_target, dst).await
+}
+
+#[cfg(target_family = "windows")]
+pub(crate) async fn create_link(
+    link_target: &Path,
+    dst: &Path,
+    resolved: &Path,
+) -> std::io::Result<()> {
+    if resolved.is_dir() {
+        fs::symlink_dir(link_target, dst).await
+    } else {
+        fs::symlink_file(link_target, dst).await
+    }
+}
+
+/// Copy the symlink `rel_path` of the app in `from` into `to` as a symlink. Returns
+/// whether the target changed and whether it existed before.
+async fn sync_link(from: &Path, to: &Path, rel_path: &Path) -> Result<(bool, bool)> {
+    let name = to_template_name(rel_path);
+    let src = from.join(rel_path);
+    let dst = to.join(rel_path);
+    let link_target = fs::read_link(&src)
+        .await
+        .with_context(|| format!("read symlink: {name}"))?;
+    let Some(resolved) = resolve_link(from, rel_path, &link_target) else {
+        bail!("symlink {name} points outside the app: {}", link_target.display());
+    };
+    let link_target = if link_target.is_absolute() {
+        relative_link(rel_path, &resolved)
+    } else {
+        link_target
+    };
+
+    let existed = match fs::symlink_metadata(&dst).await {
+        Ok(metadata) if metadata.is_symlink() => {
+            if fs::read_link(&dst).await.ok().as_ref() == Some(&link_target) {
+                return Ok((false, true));
+            }
+            fs::remove_file(&dst)
+                .await
+                .with_context(|| format!("remove symlink: {name}"))?;
+            true
+        },
+        Ok(metadata) if metadata.is_file() => {
+            fs::remove_file(&dst)
+                .await
+                .with_context(|| format!("remove file: {name}"))?;
+            true
+        },
+        Ok(_) => bail!("can't replace dir {name} with a symlink"),
+        Err(_) => false,
+    };
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("create dir: {}", parent.display()))?;
+    }
+    create_link(&link_target, &dst, &from.join(&resolved))
+        .await
+        .with_context(|| format!("create symlink: {name}"))?;
+    Ok((true, existed))
+}
+
+/// The bind mount sources declared by the compose file deployed in `target`, relative
+/// ones resolved against it.
+pub(crate) async fn volume_paths(target: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for compose_filename in COMPOSE_FILENAMES {
+        let Ok(content) = fs::read_to_string(target.join(compose_filename)).await else {
+            continue;
+        };
+        let Ok(compose) = serde_yaml::from_str::<Value>(&content) else {
+            continue;
+        };
+        let Some(services) = compose.get("services").and_then(Value::as_mapping) else {
+            continue;
+        };
+        let volumes = services
+            .values()
+            .filter_map(|service| service.get("volumes"))
+            .filter_map(Value::as_sequence)
+            .flatten();
+        for volume in volumes {
+            let source = match volume {
+                Value::String(v) => v.split(':').next(),
+                Value::Mapping(_) => volume.get("source").and_then(Value::as_str),
+                _ => None,
+            };
+            // named volumes live in docker, only paths can point into the target
+            if let Some(source) = source.filter(|v| v.starts_with('.') || v.starts_with('/')) {
+                paths.push(target.join(source));
+            }
+        }
+    }
+    paths
+}
+
+/// The bind mounted data dirs of the app in `target`. Mounted files, dirs outside of
+/// `target` and dirs holding rendered files are left out.
+pub(crate) async fn data_dirs(target: &Path) -> Result<Vec<PathBuf>> {
+    let target = target.canonicalize().context("get target abs path")?;
+    // a target synced before the rendered files were listed may have any of them rendered
+    let rendered = match fs::read_to_string(target.join(RENDERED_FILENAME)).await {
+        Ok(content) => content.lines().map(str::to_owned).collect(),
+        Err(_) => read_synced(&target).await,
+    };
+    let mut dirs = Vec::new();
+    for path in volume_paths(&target).await {
+        let Ok(path) = path.canonicalize() else {
+            continue;
+        };
+        if !path.is_dir() {
+            continue;
+        }
+        let Some(rel_path) = path.strip_prefix(&target).ok().filter(|v| !v.as_os_str().is_empty()) else {
+            warn!("leave out {}: it is outside of the render dir", path.display());
+            continue;
+        };
+        if rendered.iter().any(|v| Path::new(v).starts_with(rel_path)) {
+            warn!("leave out {}: it holds rendered files", path.display());
+            continue;
+        }
+        if !dirs.contains(&path) {
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+/// The named volumes declared by the compose file deployed in `target` by key, with the
+/// name docker knows them by in the compose project `project`.
+pub(crate) async fn named_volumes(target: &Path, project: &str) -> BTreeMap<String, String> {
+    let mut volumes = BTreeMap::new();
+    for compose_filename in COMPOSE_FILENAMES {
+        let Ok(content) = fs::read_to_string(target.join(compose_filename)).await else {
+            continue;
+        };
+        let Ok(compose) = serde_yaml::from_str::<Value>(&content) else {
+            continue;
+        };
+        let Some(declared) = compose.get("volumes").and_then(Value::as_mapping) else {
+            continue;
+        };
+        for (name, volume) in declared {
+            let Some(name) = name.as_str() else {
+                continue;
+            };
+            let external = volume
+                .get("external")
+                .and_then(Value::as_bool)
+                .unwrap_or_default();
+            let docker_name = match volume.get("name").and_then(Value::as_str) {
+                Some(v) => v.to_owned(),
+                None if external => name.to_owned(),
+                None => format!("{project}_{name}"),
+            };
+            volumes.insert(name.to_owned(), docker_name);
+        }
+    }
+    volumes
+}
+
+/// The files the last sync copied into `target`.
+pub(crate) async fn read_synced(target: &Path) -> BTreeSet<String> {
+    fs::read_to_string(target.join(SYNCED_FILENAME))
+        .await
+        .map(|content| content.lines().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Remove the synced file `name` from `target` along with the dirs it leaves empty.
+async fn remove_synced(target: &Path, name: &str) -> Result<bool> {
+    let path = target.join(name);
+    match fs::remove_file(&path).await {
+        Ok(()) => {},
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => {
+            return Err(err).with_context(|| format!("remove file: {}", path.display()));
+        },
+    }
+    let mut dir = path.parent();
+    while let Some(current) = dir.filter(|v| *v != target) {
+        if fs::remove_dir(current).await.is_err() {
+            break; // not empty
+        }
+        dir = current.parent();
+    }
+    Ok(true)
+}
+
+/// Remove the synced files `names` from `target` unless they sit in a bind mount of the
+/// deployed compose file. Returns the names of the removed ones.
+pub(crate) async fn remove_stale<'a, I>(target: &Path, names: I) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let volumes = volume_paths(target).await;
+    let mut removed = Vec::new();
+    for name in names {
+        // never trust a path which could leave the target
+        if !Path::new(name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            continue;
+        }
+        if volumes
+            .iter()
+            .any(|volume| target.join(name).starts_with(volume))
+        {
+            debug!("keep {name} which is inside a volume");
+            continue;
+        }
+        if remove_synced(target, name).await? {
+            removed.push(name.clone());
+        }
+    }
+    Ok(removed)
+}
+
+/// Sync the app in `from` into `to`, skipping what `ignore` matches and `to` itself.
+/// Only files which changed are copied, `templates` always are. Modes are kept and
+/// symlinks stay symlinks, which must not point outside the app. A file already in a bind
+/// mounted data dir belongs to the running app and is never overwritten. Files synced
+/// before but gone from the app are removed unless they sit in a bind mount of the
+/// deployed compose file, anything else in `to` is left alone.
+pub(super) async fn sync(
+    from: &Path,
+    to: &Path,
+    ignore: &Gitignore,
+    templates: &[String],
+) -> Result<SyncReport> {
+    let from = from.canonicalize().context("get from abs path")?;
+    let to = to.canonicalize().context("get to abs path")?;
+
+    let mut listing = Listing::default();
+    list_entries(&from, &to, ignore, PathBuf::new(), &mut listing).await?;
+    listing.files.sort_unstable();
+    listing.links.sort_unstable();
+
+    let data_dirs = data_dirs(&to).await?;
+    let mut report = SyncReport::default();
+    let mut synced = BTreeSet::new();
+    for rel_path in listing.files {
+        let name = to_template_name(&rel_path);
+        let src = from.join(&rel_path);
+        let dst = to.join(&rel_path);
+        let is_template = templates.contains(&name);
+        synced.insert(name.clone());
+
+        if !is_template
+            && data_dirs.iter().any(|v| dst.starts_with(v))
+            && fs::symlink_metadata(&dst).await.is_ok()
+        {
+            debug!("keep {name} which is in a data dir");
+            report.unchanged += 1;
+            continue;
+        }
+
+        if !is_template && is_unchanged(&src, &dst).await? {
+            report.unchanged += 1;
+            continue;
+        }
+        let existed = match fs::symlink_metadata(&dst).await {
+            Ok(metadata) if metadata.is_symlink() => {
+                // don't write through a link which used to be there
+                fs::remove_file(&dst)
+                    .await
+                    .with_context(|| format!("remove symlink: {name}"))?;
+                true
+            },
+            Ok(_) => true,
+            Err(_) => false,
+        };
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("create dir: {}", parent.display()))?;
+        }
+        fs::copy(&src, &dst)
+            .await
+            .with_context(|| format!("copy file: {name}"))?;
+        let mtime = FileTime::from_last_modification_time(
+            &fs::metadata(&src).await.context("get file metadata")?,
+        );
+        filetime::set_file_mtime(&dst, mtime).context("set file mtime")?;
+
+        if is_template {
+            continue;
+        }
+        if existed {
+            report.updated.push(name);
+        } else {
+            report.added.push(name);
+        }
+    }
+    for rel_path in listing.links {
+        let name = to_template_name(&rel_path);
+        synced.insert(name.clone());
+        match sync_link(&from, &to, &rel_path).await? {
+            (false, _) => report.unchanged += 1,
+            (true, true) => report.updated.push(name),
+            (true, false) => report.added.push(name),
+        }
+    }
+
+    report.removed = remove_stale(&to, read_synced(&to).await.difference(&synced)).await?;
+
+    let content: String = synced.iter().map(|name| format!("{name}\n")).collect();
+    fs::write(to.join(SYNCED_FILENAME), content)
+        .await
+        .context("write synced file list")?;
+    let content: String = synced
+        .iter()
+        .filter(|name| templates.contains(name))
+        .map(|name| format!("{name}\n"))
+        .collect();
+    fs::write(to.join(RENDERED_FILENAME), content)
+        .await
+        .context("write rendered file list")?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+    use super::*;
+
+    /// An app with a config file, a bind mounted data dir and a plain file, synced once
+    /// into a target. Returns the temp root, the app and the target dir.
+    async fn synced_app() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let app = root.path().join("app");
+        let target = root.path().join("target");
+        std::fs::create_dir_all(app.join("config")).unwrap();
+        std::fs::create_dir_all(app.join("data")).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+        std::fs::write(app.join("config/a.conf"), "a").unwrap();
+        std::fs::write(app.join("data/.gitkeep"), "").unwrap();
+        std::fs::write(app.join("b.txt"), "b").unwrap();
+        std::fs::write(
+            app.join("docker-compose.yaml"),
+            "services:\n  app:\n    volumes:\n      - ./data:/data\n",
+        )
+        .unwrap();
+
+        let report = sync(&app, &target, &no_ignore(&app), &[]).await.unwrap();
+        assert_eq!(
+            report.added,
+            [
+                "b.txt",
+                "config/a.conf",
+                "data/.gitkeep",
+                "docker-compose.yaml"
+            ]
+        );
+        (root, app, target)
+    }
+
+    fn no_ignore(app: &Path) -> Gitignore {
+        GitignoreBuilder::new(app).build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sync_updates_and_removes() {
+        let (_root, app, target) = synced_app().await;
+        std::fs::write(app.join("config/a.conf"), "changed").unwrap();
+        std::fs::remove_file(app.join("b.txt")).unwrap();
+
+        let report = sync(&app, &target, &no_ignore(&app), &[]).await.unwrap();
+        assert!(report.added.is_empty());
+        assert_eq!(report.updated, ["config/a.conf"]);
+        assert_eq!(report.removed, ["b.txt"]);
+        assert_eq!(report.unchanged, 2);
+        assert!(!target.join("b.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_sync_keeps_runtime_files() {
+        let (_root, app, target) = synced_app().await;
+        // files created by the app at runtime, in a volume and beside it
+        std::fs::write(target.join("data/dump.rdb"), "data").unwrap();
+        std::fs::write(target.join("runtime.pid"), "1").unwrap();
+        // the app stopped shipping its data dir, which is still mounted
+        std::fs::remove_dir_all(app.join("data")).unwrap();
+
+        let report = sync(&app, &target, &no_ignore(&app), &[]).await.unwrap();
+        assert!(report.removed.is_empty());
+        assert!(target.join("data/.gitkeep").exists());
+        assert!(target.join("data/dump.rdb").exists());
+        assert!(target.join("runtime.pid").exists());
+    }
+
+    #[tokio::test]
+    async fn test_sync_keeps_data() {
+        let (_root, app, target) = synced_app().await;
+        std::fs::write(target.join("data/dump.rdb"), "live").unwrap();
+        // left in the app by a local run
+        std::fs::write(app.join("data/dump.rdb"), "stale").unwrap();
+        std::fs::write(app.join("data/new.rdb"), "new").unwrap();
+
+        let report = sync(&app, &target, &no_ignore(&app), &[]).await.unwrap();
+        assert_eq!(report.added, ["data/new.rdb"]);
+        assert!(report.updated.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(target.join("data/dump.rdb")).unwrap(),
+            "live"
+        );
+    }
+
+    #[test]
+    fn test_resolve_link() {
+        let root = Path::new("/app");
+        let link = Path::new("config/current.conf");
+        assert_eq!(
+            resolve_link(root, link, Path::new("v2/redis.conf")),
+            Some(PathBuf::from("config/v2/redis.conf"))
+        );
+        assert_eq!(
+            resolve_link(root, link, Path::new("../scripts/init.sh")),
+            Some(PathBuf::from("scripts/init.sh"))
+        );
+        assert_eq!(
+            resolve_link(root, link, Path::new("/app/scripts/init.sh")),
+            Some(PathBuf::from("scripts/init.sh"))
+        );
+        assert_eq!(
+            resolve_link(root, link, Path::new("../../etc/passwd")),
+            None
+        );
+        assert_eq!(resolve_link(root, link, Path::new("/etc/passwd")), None);
+        assert_eq!(
+            relative_link(link, Path::new("scripts/init.sh")),
+            PathBuf::from("../scripts/init.sh")
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use handlebars::template::{HelperTemplate, Parameter, Template, TemplateElement};
+use handlebars::{to_json, JsonValue};
+
+use crate::manifest::Manifest;
+
+/// The manifest sections which templates can reference by key.
+const SECTIONS: [&str; 2] = ["ports", "variables"];
+
+/// The helpers whose first param may be missing, they fall back to another one.
+const FALLBACK_HELPERS: [&str; 1] = ["default"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub(crate) struct Finding {
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+struct Reference {
+    path: String,
+    line: Option<usize>,
+    column: Option<usize>,
+    /// Whether the template renders fine without it.
+    optional: bool,
+}
+
+/// Check the `ports.*` and `variables.*` references of every template against
+/// the manifest. References to undefined keys are errors unless a helper like
+/// `default` falls back on them, declared keys which are never referenced are
+/// warnings.
+pub(crate) fn lint(manifest: &Manifest, templates: &[(String, String)]) -> Vec<Finding> {
+    let data = to_json(manifest);
+    let mut findings = Vec::new();
+    let mut used = HashSet::new();
+
+    for (template_name, source) in templates {
+        let template = match Template::compile(source) {
+            Ok(v) => v,
+            Err(err) => {
+                findings.push(Finding {
+                    severity: Severity::Error,
+                    message: format!("{template_name}: {err}"),
+                });
+                continue;
+            },
+        };
+
+        let mut references = Vec::new();
+        collect_template(&template, None, &mut references);
+        for reference in references {
+            let segments: Vec<&str> = reference
+                .path
+                .split(['.', '/'])
+                .map(|segment| segment.trim_start_matches('[').trim_end_matches(']'))
+                .filter(|segment| !segment.is_empty())
+                .collect();
+            if segments.len() < 2 {
+                continue;
+            }
+            used.insert((segments[0].to_owned(), segments[1].to_owned()));
+
+            if !reference.optional && resolve(&data, &segments).is_none() {
+                let location = match (reference.line, reference.column) {
+                    (Some(line), Some(col)) => format!("{template_name}:{line}:{col}"),
+                    _ => template_name.to_owned(),
+                };
+                findings.push(Finding {
+                    severity: Severity::Error,
+                    message: format!(
+                        "{location}: '{}' is not defined in the manifest",
+                        segments.join(".")
+                    ),
+                });
+            }
+        }
+    }
+
+    let mut unused = Vec::new();
+    for (section, declared) in [
+        ("ports", manifest.ports.keys().collect::<Vec<_>>()),
+        ("variables", manifest.variables.keys().collect()),
+    ] {
+        for name in declared {
+            if !used.contains(&(section.to_owned(), name.clone())) {
+                unused.push(format!(
+                    "'{section}.{name}' is declared but never referenced by any template"
+                ));
+            }
+        }
+    }
+    unused.sort_unstable();
+    findings.extend(unused.into_iter().map(|message| Finding {
+        severity: Severity::Warning,
+        message,
+    }));
+    findings
+}
+
+fn resolve<'a>(data: &'a JsonValue, segments: &[&str]) -> Option<&'a JsonValue> {
+    segments
+        .iter()
+        .try_fold(data, |current, segment| current.get(*segment))
+}
+
+fn collect_template(
+    template: &Template,
+    location: Option<(usize, usize)>,
+    references: &mut Vec<Reference>,
+) {
+    for (idx, element) in template.elements.iter().enumerate() {
+        // nested templates without a mapping report the location of their block
+        let location = template
+            .mapping
+            .get(idx)
+            .map(|mapping| (mapping.0, mapping.1))
+            .or(location);
+        match element {
+            TemplateElement::Expression(ht)
+            | TemplateElement::HtmlExpression(ht)
+            | TemplateElement::HelperBlock(ht) => collect_helper(ht, location, references),
+            TemplateElement::DecoratorExpression(dt)
+            | TemplateElement::DecoratorBlock(dt)
+            | TemplateElement::PartialExpression(dt)
+            | TemplateElement::PartialBlock(dt) => {
+                for param in dt.params.iter().chain(dt.hash.values()) {
+                    collect_parameter(param, location, false, references);
+                }
+                if let Some(template) = &dt.template {
+                    collect_template(template, location, references);
+                }
+            },
+            TemplateElement::RawString(_) | TemplateElement::Comment(_) => {},
+        }
+    }
+}
+
+fn collect_helper(
+    ht: &HelperTemplate,
+    location: Option<(usize, usize)>,
+    references: &mut Vec<Reference>,
+) {
+    collect_parameter(&ht.name, location, false, references);
+    let fallback = ht
+        .name
+        .as_name()
+        .map_or(false, |v| FALLBACK_HELPERS.contains(&v));
+    for (idx, param) in ht.params.iter().enumerate() {
+        collect_parameter(param, location, fallback && idx == 0, references);
+    }
+    for param in ht.hash.values() {
+        collect_parameter(param, location, false, references);
+    }
+    for template in ht.template.iter().chain(ht.inverse.iter()) {
+        collect_template(template, location, references);
+    }
+}
+
+fn collect_parameter(
+    param: &Parameter,
+    location: Option<(usize, usize)>,
+    optional: bool,
+    references: &mut Vec<Reference>,
+) {
+    match param {
+        Parameter::Name(_) | Parameter::Path(_) => {
+            let Some(raw) = param.as_name() else {
+                return;
+            };
+            let path = raw.trim_start_matches("@root.").trim_start_matches("this.");
+            if SECTIONS
+                .iter()
+                .any(|section| path.starts_with(&format!("{section}.")))
+            {
+                references.push(Reference {
+                    path: path.to_owned(),
+                    line: location.map(|v| v.0),
+                    column: location.map(|v| v.1),
+                    optional,
+                });
+            }
+        },
+        Parameter::Subexpression(subexpression) => {
+            if let TemplateElement::Expression(ht) = subexpression.as_element() {
+                collect_helper(ht, location, references);
+            }
+        },
+        Parameter::Literal(_) => {},
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lint_references() {
+        let manifest: Manifest = serde_yaml::from_str(
+            r#"
+metadata:
+  name: redis
+  desc: fast kv database.
+  tags: []
+  version: 0.1.0
+templates:
+  - docker-compose.yaml
+ports:
+  redis:
+    ip: 127.0.0.1
+    port: 6379
+    desc: redis
+variables:
+  password:
+    name: password
+    desc: password
+    value: your-secret
+  timeout:
+    name: timeout
+    desc: timeout
+    value: 0
+"#,
+        )
+        .unwrap();
+        let templates = vec![(
+            "docker-compose.yaml".to_owned(),
+            "port: {{ports.redis.port}}\npass: {{variables.pasword.value}}\n\
+             timeout: {{default variables.timeout.value (default variables.tmeout.value 0)}}\n\
+             keepalive: {{default variables.keepalive.value variables.keepalve.value}}\n"
+                .to_owned(),
+        )];
+
+        let findings = lint(&manifest, &templates);
+        let errors: Vec<_> = findings
+            .iter()
+            .filter(|v| v.severity == Severity::Error)
+            .map(|v| v.message.as_str())
+            .collect();
+        let warnings: Vec<_> = findings
+            .iter()
+            .filter(|v| v.severity == Severity::Warning)
+            .map(|v| v.message.as_str())
+            .collect();
+        // a missing value is fine for default, a missing fallback is not
+        assert_eq!(
+            errors,
+            [
+                "docker-compose.yaml:2:7: 'variables.pasword.value' is not defined in the manifest",
+                "docker-compose.yaml:4:12: 'variables.keepalve.value' is not defined in the manifest",
+            ]
+        );
+        assert_eq!(
+            warnings,
+            ["'variables.password' is declared but never referenced by any template"]
+        );
+    }
+}
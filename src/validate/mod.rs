@@ -0,0 +1,204 @@
+mod lint;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use futures::{pin_mut, select, FutureExt};
+use log::{error, warn};
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+
+use self::lint::{lint, Finding, Severity};
+use crate::hook::{env_key, Phase};
+use crate::instance::INSTANCES_DIR;
+use crate::manifest::{ContainerMode, Manifest};
+use crate::up::collieignore;
+use crate::{template_files, DEFAULT_TARGET_DIR, MANIFEST_FILENAME};
+
+/// The declared hooks of `manifest` which are required but whose script is missing in
+/// the app `dir`, or which exec in a container when there may be none.
+fn check_hooks(dir: &Path, manifest: &Manifest) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for phase in Phase::ALL {
+        let Some(hook) = manifest.hooks.get(phase) else {
+            continue;
+        };
+        if hook.required && !dir.join(&hook.path).is_file() {
+            findings.push(Finding {
+                severity: Severity::Error,
+                message: format!(
+                    "{MANIFEST_FILENAME}: {phase} hook {} is required but missing",
+                    hook.path.display()
+                ),
+            });
+        }
+        let exec = hook
+            .container
+            .as_ref()
+            .map_or(false, |v| v.mode == ContainerMode::Exec);
+        if exec && !phase.has_running_containers() {
+            findings.push(Finding {
+                severity: Severity::Error,
+                message: format!(
+                    "{MANIFEST_FILENAME}: {phase} hook can't exec in a container, none may be running then, use mode: run"
+                ),
+            });
+        }
+    }
+    findings
+}
+
+/// The ports and variables of `manifest` which are passed to the hooks under the same
+/// env name, like port `db_ip` and the ip of port `db`.
+fn check_env_names(manifest: &Manifest) -> Vec<Finding> {
+    let mut owners: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for name in manifest.ports.keys() {
+        let port_key = env_key("COLLIE_PORT_", name);
+        owners
+            .entry(format!("{port_key}_IP"))
+            .or_default()
+            .push(format!("the ip of port {name}"));
+        owners
+            .entry(port_key)
+            .or_default()
+            .push(format!("port {name}"));
+    }
+    for name in manifest.variables.keys() {
+        owners
+            .entry(env_key("COLLIE_VAR_", name))
+            .or_default()
+            .push(format!("variable {name}"));
+    }
+    owners
+        .into_iter()
+        .filter(|(_, owners)| owners.len() > 1)
+        .map(|(env_name, mut owners)| {
+            owners.sort_unstable();
+            Finding {
+                severity: Severity::Error,
+                message: format!(
+                    "{MANIFEST_FILENAME}: {} are all passed to hooks as {env_name}, rename one",
+                    owners.join(", ")
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Refuse to deploy `manifest` of the app in `dir` for the errors [`validate`] reports
+/// about its hooks and their env, which the deploy would only run into half way.
+pub(crate) fn check_deployable(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let errors: Vec<_> = check_hooks(dir, manifest)
+        .into_iter()
+        .chain(check_env_names(manifest))
+        .filter(|v| v.severity == Severity::Error)
+        .map(|v| v.message)
+        .collect();
+    if !errors.is_empty() {
+        bail!("{}", errors.join("; "));
+    }
+    Ok(())
+}
+
+pub(super) async fn validate<P: AsRef<Path>>(dir: P, token: CancellationToken) -> Result<()> {
+    let dir = dir.as_ref();
+
+    let wait_for_cancel = token.cancelled().fuse();
+    pin_mut!(wait_for_cancel);
+
+    let manifest = select! {
+        _ = wait_for_cancel => return Ok(()),
+        result = Manifest::from_dir(dir).fuse() => result?
+    };
+
+    let ignore = dir
+        .canonicalize()
+        .context("get app abs path")
+        .and_then(|dir| collieignore::load(&dir))?;
+    let template_rel_paths = template_files::expand(
+        dir,
+        &manifest.templates,
+        &manifest.templates_exclude,
+        &[
+            PathBuf::from(DEFAULT_TARGET_DIR),
+            PathBuf::from(INSTANCES_DIR),
+        ],
+        &ignore,
+    )
+    .await?;
+    let mut templates = Vec::with_capacity(template_rel_paths.len());
+    for template_rel_path in template_rel_paths {
+        let template_file_path = dir.join(&template_rel_path);
+        let template_content = select! {
+            _ = wait_for_cancel => return Ok(()),
+            result = fs::read_to_string(&template_file_path).fuse() => {
+                result.with_context(|| format!("read template content: {template_rel_path}"))?
+            }
+        };
+        templates.push((template_rel_path, template_content));
+    }
+
+    let mut findings = check_hooks(dir, &manifest);
+    findings.extend(check_env_names(&manifest));
+    findings.extend(lint(&manifest, &templates));
+    let mut errors = 0;
+    for finding in &findings {
+        match finding.severity {
+            Severity::Error => {
+                errors += 1;
+                error!("{finding}");
+            },
+            Severity::Warning => warn!("{finding}"),
+        }
+    }
+    if errors > 0 {
+        bail!("{errors} error(s) found");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_env_names() {
+        let manifest: Manifest = serde_yaml::from_str(
+            r#"
+metadata:
+  name: redis
+  desc: fast kv database.
+  tags: []
+  version: 0.1.0
+templates: []
+ports:
+  db:
+    ip: 127.0.0.1
+    port: 5432
+    desc: db
+  db_ip:
+    ip: 127.0.0.1
+    port: 5433
+    desc: db
+variables:
+  tcp-keepalive:
+    name: tcp-keepalive
+    desc: keepalive
+    value: 0
+  tcp_keepalive:
+    name: tcp_keepalive
+    desc: keepalive
+    value: 0
+"#,
+        )
+        .unwrap();
+        let messages: Vec<_> = check_env_names(&manifest)
+            .into_iter()
+            .map(|v| v.message)
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("port db_ip, the ip of port db"));
+        assert!(messages[1].contains("COLLIE_VAR_TCP_KEEPALIVE"));
+    }
+}